@@ -0,0 +1,49 @@
+#[cfg(feature = "std")]
+use std::error as stderror;
+use crate::{Block, BlockExecutor};
+
+/// Executor computing the new state from the old state and a block,
+/// instead of mutating the state in place.
+pub trait FunctionalExecutor {
+	#[cfg(feature = "std")]
+	/// Error type
+	type Error: stderror::Error + 'static;
+	#[cfg(not(feature = "std"))]
+	/// Error type
+	type Error: 'static;
+	/// Block type
+	type Block: Block;
+	/// State type
+	type State;
+
+	/// Apply the block to the state, returning the new state.
+	fn apply(
+		&self,
+		block: &Self::Block,
+		state: Self::State,
+	) -> Result<Self::State, Self::Error>;
+}
+
+/// Adapter using a functional executor as a block executor. The state is
+/// cloned, applied, and replaced on success; on failure it is left
+/// untouched.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionalAdapter<F>(pub F);
+
+impl<F: FunctionalExecutor> BlockExecutor for FunctionalAdapter<F> where
+	F::State: Clone,
+{
+	type Error = F::Error;
+	type Block = F::Block;
+	type Externalities = F::State;
+	type Event = ();
+
+	fn execute_block(
+		&self,
+		block: &Self::Block,
+		state: &mut Self::Externalities,
+	) -> Result<(), Self::Error> {
+		*state = self.0.apply(block, state.clone())?;
+		Ok(())
+	}
+}
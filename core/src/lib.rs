@@ -5,4 +5,9 @@
 extern crate alloc;
 
 mod traits;
+mod simple;
+mod functional;
+
 pub use crate::traits::*;
+pub use crate::simple::SimpleBlock;
+pub use crate::functional::{FunctionalExecutor, FunctionalAdapter};
@@ -0,0 +1,61 @@
+use alloc::vec::Vec;
+use core::hash;
+use crate::Block;
+
+/// A simple block storing its id, parent id and an opaque payload. Usable
+/// without std.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimpleBlock<Id> {
+	/// Id of the block.
+	pub id: Id,
+	/// Parent id of the block. None if this block is genesis.
+	pub parent_id: Option<Id>,
+	/// Opaque payload of the block.
+	pub payload: Vec<u8>,
+}
+
+impl<Id> SimpleBlock<Id> {
+	/// Create a new genesis block.
+	pub fn genesis(id: Id, payload: Vec<u8>) -> Self {
+		Self { id, parent_id: None, payload }
+	}
+
+	/// Create a new block with the given parent.
+	pub fn new(id: Id, parent_id: Id, payload: Vec<u8>) -> Self {
+		Self { id, parent_id: Some(parent_id), payload }
+	}
+}
+
+impl<Id: Clone + Eq + hash::Hash> Block for SimpleBlock<Id> {
+	type Identifier = Id;
+
+	fn id(&self) -> Id {
+		self.id.clone()
+	}
+
+	fn parent_id(&self) -> Option<Id> {
+		self.parent_id.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+
+	#[test]
+	fn simple_blocks_form_a_chain() {
+		let genesis = SimpleBlock::genesis(0u64, Vec::new());
+		let mut chain = vec![genesis];
+		for id in 1..5 {
+			let parent = chain.last().expect("Chain starts with genesis; qed").id();
+			chain.push(SimpleBlock::new(id, parent, vec![id as u8]));
+		}
+
+		assert_eq!(chain[0].parent_id(), None);
+		for pair in chain.windows(2) {
+			assert_eq!(pair[1].parent_id(), Some(pair[0].id()));
+		}
+		assert_eq!(chain[4].payload, vec![4]);
+	}
+}
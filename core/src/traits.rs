@@ -1,6 +1,7 @@
 #[cfg(feature = "std")]
 use std::error as stderror;
 use alloc::vec::Vec;
+use alloc::boxed::Box;
 use core::hash;
 
 /// A block contains a hash, and reference a parent block via parent hash.
@@ -14,6 +15,31 @@ pub trait Block: Clone {
 	fn parent_id(&self) -> Option<Self::Identifier>;
 }
 
+/// A block that can be separated into header and body. The header alone
+/// is enough to derive the block's id and parent id.
+pub trait HasHeader: Block {
+	/// Header type of the block.
+	type Header: Block<Identifier=Self::Identifier>;
+
+	/// Get the block header.
+	fn header(&self) -> Self::Header;
+}
+
+/// A block tagged with the spec version of the runtime that built it.
+pub trait VersionedBlock: Block {
+	/// Get the spec version of the block.
+	fn spec_version(&self) -> u32;
+}
+
+/// A block that records its author.
+pub trait AuthoredBlock: Block {
+	/// Author type of the block.
+	type Author: Clone + Eq + hash::Hash;
+
+	/// Get the author of the block.
+	fn author(&self) -> Self::Author;
+}
+
 /// A value where the key is contained in.
 pub trait Auxiliary<B: Block>: Clone {
 	/// Key type
@@ -42,6 +68,12 @@ pub trait AsExternalities<E: ?Sized> {
 	fn as_externalities(&mut self) -> &mut E;
 }
 
+impl<E: ?Sized, T: AsExternalities<E>> AsExternalities<E> for &mut T {
+	fn as_externalities(&mut self) -> &mut E {
+		(**self).as_externalities()
+	}
+}
+
 /// Null externalities.
 pub trait NullExternalities { }
 
@@ -56,10 +88,17 @@ impl AsExternalities<dyn NullExternalities> for () {
 pub trait StorageExternalities<Error> {
 	/// Read storage value.
 	fn read_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+	/// Read storage values of several keys at once.
+	fn read_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+		keys.iter().map(|key| self.read_storage(key)).collect()
+	}
 	/// Write storage value.
 	fn write_storage(&mut self, key: Vec<u8>, value: Vec<u8>);
 	/// Remove storage value.
 	fn remove_storage(&mut self, key: &[u8]);
+	/// Iterate over all storage key-value pairs whose key starts with the
+	/// given prefix.
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_>;
 }
 
 /// Block executor
@@ -74,6 +113,8 @@ pub trait BlockExecutor {
 	type Block: Block;
 	/// Externalities type
 	type Externalities: ?Sized;
+	/// Event type
+	type Event;
 
 	/// Execute the block via a block object and given state.
 	fn execute_block(
@@ -81,8 +122,43 @@ pub trait BlockExecutor {
 		block: &Self::Block,
 		state: &mut Self::Externalities
 	) -> Result<(), Self::Error>;
+
+	/// Execute the block via a block object and given state, returning
+	/// events emitted during execution.
+	fn execute_block_with_events(
+		&self,
+		block: &Self::Block,
+		state: &mut Self::Externalities
+	) -> Result<Vec<Self::Event>, Self::Error> {
+		self.execute_block(block, state)?;
+		Ok(Vec::new())
+	}
+
+	/// Make the parent block id available to execution, typically by
+	/// storing it in the state under a reserved key. Called on the parent
+	/// state before a child block is built or executed.
+	fn set_parent_context(
+		&self,
+		_state: &mut Self::Externalities,
+		_parent_id: &<Self::Block as Block>::Identifier,
+	) { }
+
+	/// Spec version of the runtime. Blocks of other spec versions are
+	/// rejected by `import::check_spec_version`.
+	fn spec_version(&self) -> u32 {
+		0
+	}
+
+	/// Storage keys read or written when executing the block. None if the
+	/// executor cannot determine them ahead of execution.
+	fn accessed_keys(&self, _block: &Self::Block) -> Option<Vec<Vec<u8>>> {
+		None
+	}
 }
 
+/// Weight of an applied extrinsic.
+pub type Weight = u64;
+
 /// Builder executor
 pub trait ExtrinsicBuilder: BlockExecutor {
 	/// Build block type
@@ -100,13 +176,18 @@ pub trait ExtrinsicBuilder: BlockExecutor {
 		inherent: Self::Inherent,
 	) -> Result<Self::BuildBlock, Self::Error>;
 
-	/// Apply extrinsic to a given block.
+	/// Maximum cumulative weight of extrinsics in a block.
+	fn max_block_weight(&self) -> Weight {
+		Weight::MAX
+	}
+
+	/// Apply extrinsic to a given block, returning its weight.
 	fn apply_extrinsic(
 		&self,
 		block: &mut Self::BuildBlock,
 		extrinsic: Self::Extrinsic,
 		state: &mut Self::Externalities,
-	) -> Result<(), Self::Error>;
+	) -> Result<Weight, Self::Error>;
 
 	/// Finalize a block.
 	fn finalize_block(
@@ -115,3 +196,14 @@ pub trait ExtrinsicBuilder: BlockExecutor {
 		state: &mut Self::Externalities,
 	) -> Result<(), Self::Error>;
 }
+
+/// Sealer of built blocks.
+pub trait Sealer {
+	/// Unsealed block type
+	type Unsealed;
+	/// Sealed block type
+	type Sealed;
+
+	/// Seal a built block.
+	fn seal(&self, unsealed: Self::Unsealed) -> Self::Sealed;
+}
@@ -3,19 +3,26 @@ extern crate parity_codec as codec;
 mod runtime;
 
 use blockchain::backend::{SharedMemoryBackend, KeyValueMemoryState, ChainQuery, ImportOperation, ImportLock};
-use blockchain::import::ImportAction;
-use blockchain::{Block as BlockT, SimpleBuilderExecutor, AsExternalities};
+use blockchain::import::{ImportAction, BlockProducer};
+use blockchain::Block as BlockT;
 use blockchain_network_simple::{BestDepthImporter, BestDepthStatusProducer};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
 use clap::{App, SubCommand, AppSettings, Arg};
 use crate::runtime::{Block, Executor};
 
+const DEFAULT_BLOCK_TIME_MILLIS: u64 = 1000;
+
 fn main() {
 	let matches = App::new("Blockchain counter demo")
 		.setting(AppSettings::SubcommandRequiredElseHelp)
 		.subcommand(SubCommand::with_name("local")
-					.about("Start a local test network"))
+					.about("Start a local test network")
+					.arg(Arg::with_name("block-time")
+						 .long("block-time")
+						 .takes_value(true)
+						 .help("Target block time in milliseconds")))
 		.subcommand(SubCommand::with_name("libp2p")
 					.about("Start a libp2p instance")
 					.arg(Arg::with_name("port")
@@ -25,99 +32,155 @@ fn main() {
 						 .help("Port to listen on"))
 					.arg(Arg::with_name("author")
 						 .long("author")
-						 .help("Whether to author blocks")))
+						 .help("Whether to author blocks"))
+					.arg(Arg::with_name("block-time")
+						 .long("block-time")
+						 .takes_value(true)
+						 .help("Target block time in milliseconds")))
 		.get_matches();
 
-	if let Some(_) = matches.subcommand_matches("local") {
-		local_sync();
+	if let Some(matches) = matches.subcommand_matches("local") {
+		local_sync(block_time(matches));
 		return
 	}
 
 	if let Some(matches) = matches.subcommand_matches("libp2p") {
 		let port = matches.value_of("port").unwrap_or("37365");
 		let author = matches.is_present("author");
-		libp2p_sync(port, author);
+		libp2p_sync(port, author, block_time(matches));
 		return
 	}
 }
 
-fn local_sync() {
+fn block_time(matches: &clap::ArgMatches) -> Duration {
+	let millis = matches.value_of("block-time")
+		.map(|v| v.parse().expect("Block time must be a number"))
+		.unwrap_or(DEFAULT_BLOCK_TIME_MILLIS);
+	Duration::from_millis(millis)
+}
+
+fn local_sync(block_time: Duration) {
 	let genesis_block = Block::genesis();
-	let (backend_build, lock_build) = (
-		SharedMemoryBackend::<_, (), KeyValueMemoryState>::new_with_genesis(
-			genesis_block.clone(),
-			Default::default()
-		),
-		ImportLock::new()
-	);
+	let lock_build = ImportLock::new();
+	let backend_build = SharedMemoryBackend::<_, (), KeyValueMemoryState>::new_with_genesis(
+		genesis_block.clone(),
+		Default::default()
+	).with_import_lock(lock_build.clone());
 	let mut peers = HashMap::new();
 	for peer_id in 0..4 {
 		let (backend, lock) = if peer_id == 0 {
 			(backend_build.clone(), lock_build.clone())
 		} else {
+			let lock = ImportLock::new();
 			(
 				SharedMemoryBackend::<_, (), KeyValueMemoryState>::new_with_genesis(
 					genesis_block.clone(),
 					Default::default()
-				),
-				ImportLock::new()
+				).with_import_lock(lock.clone()),
+				lock
 			)
 		};
-		let importer = BestDepthImporter::new(Executor, backend.clone(), lock.clone());
+		let importer = BestDepthImporter::new(Executor::default(), backend.clone(), lock.clone());
 		let status = BestDepthStatusProducer::new(backend.clone());
 		peers.insert(peer_id, (backend, lock, importer, status));
 	}
 	thread::spawn(move || {
-		builder_thread(backend_build, lock_build);
+		builder_thread(backend_build, lock_build, block_time);
 	});
 
 	blockchain_network_simple::local::start_local_simple_sync(peers);
 }
 
-fn libp2p_sync(port: &str, author: bool) {
+fn libp2p_sync(port: &str, author: bool, block_time: Duration) {
 	let genesis_block = Block::genesis();
+	let lock = ImportLock::new();
 	let backend = SharedMemoryBackend::<_, (), KeyValueMemoryState>::new_with_genesis(
 		genesis_block.clone(),
 		Default::default()
-	);
-	let lock = ImportLock::new();
-	let importer = BestDepthImporter::new(Executor, backend.clone(), lock.clone());
+	).with_import_lock(lock.clone());
+	let importer = BestDepthImporter::new(Executor::default(), backend.clone(), lock.clone());
 	let status = BestDepthStatusProducer::new(backend.clone());
 	if author {
 		let backend_build = backend.clone();
 		let lock_build = lock.clone();
 		thread::spawn(move || {
-			builder_thread(backend_build, lock_build);
+			builder_thread(backend_build, lock_build, block_time);
 		});
 	}
 	blockchain_network_simple::libp2p::start_network_simple_sync(port, backend, lock, importer, status);
 }
 
-fn builder_thread(backend_build: SharedMemoryBackend<Block, (), KeyValueMemoryState>, lock: ImportLock) {
-	loop {
-		let head = backend_build.head();
-		let executor = Executor;
-		println!("Building on top of {}", head);
+fn builder_thread(
+	backend_build: SharedMemoryBackend<Block, (), KeyValueMemoryState>,
+	lock: ImportLock,
+	block_time: Duration,
+) {
+	let producer = BlockProducer::new(Executor::default(), backend_build.clone());
 
-		// Build a block.
-		let parent_block = backend_build.block_at(&head).unwrap();
-		let mut pending_state = backend_build.state_at(&head).unwrap();
+	loop {
+		build_paced(&producer, &backend_build, &lock, block_time);
+	}
+}
 
-		let mut unsealed_block = executor.initialize_block(
-			&parent_block, pending_state.as_externalities(), ()
-		).unwrap();
-		executor.finalize_block(
-			&mut unsealed_block, pending_state.as_externalities(),
-		).unwrap();
+/// Build a block on top of the current head and import it, then wait until
+/// the target block time elapses, whether or not the block was imported.
+/// Returns whether the block was imported.
+fn build_paced(
+	producer: &BlockProducer<Executor, SharedMemoryBackend<Block, (), KeyValueMemoryState>>,
+	backend_build: &SharedMemoryBackend<Block, (), KeyValueMemoryState>,
+	lock: &ImportLock,
+	block_time: Duration,
+) -> bool {
+	let started = Instant::now();
+	let head = backend_build.head();
+	println!("Building on top of {}", head);
 
-		let block = unsealed_block.seal();
+	// Build a block.
+	let (block, pending_state) = producer.produce_sealed((), Vec::new()).unwrap();
 
-		// Import the built block.
-		let mut build_importer = ImportAction::new(&executor, &backend_build, lock.lock());
+	// Import the built block, unless another block became head while
+	// building.
+	let mut build_importer = ImportAction::new(backend_build, lock.lock());
+	let imported = if backend_build.head() != head {
+		println!("Head changed while building on top of {}, skipping", head);
+		false
+	} else {
 		let new_block_hash = block.id();
 		let op = ImportOperation { block, state: pending_state };
 		build_importer.import_raw(op);
 		build_importer.set_head(new_block_hash);
 		build_importer.commit().unwrap();
+		true
+	};
+
+	// Wait until the target block time elapses.
+	if let Some(remaining) = block_time.checked_sub(started.elapsed()) {
+		thread::sleep(remaining);
+	}
+
+	imported
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::runtime::SealPolicy;
+
+	#[test]
+	fn blocks_are_paced_to_block_time() {
+		let lock = ImportLock::new();
+		let backend = SharedMemoryBackend::<_, (), KeyValueMemoryState>::new_with_genesis(
+			Block::genesis(),
+			Default::default(),
+		).with_import_lock(lock.clone());
+		let producer = BlockProducer::new(Executor::new(SealPolicy::NoProof), backend.clone());
+		let block_time = Duration::from_millis(50);
+
+		let started = Instant::now();
+		for _ in 0..3 {
+			assert!(build_paced(&producer, &backend, &lock, block_time));
+		}
+		assert!(started.elapsed() >= block_time * 3);
+		assert_eq!(backend.depth_at(&backend.head()).unwrap(), 3);
 	}
 }
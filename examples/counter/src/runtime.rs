@@ -1,18 +1,34 @@
 use primitive_types::H256;
 use blockchain::{
-	Block as BlockT, BlockExecutor,
-	SimpleBuilderExecutor, StorageExternalities,
+	Block as BlockT, HasHeader, VersionedBlock, BlockExecutor,
+	ExtrinsicBuilder, Sealer, StorageExternalities, Weight,
 };
+use blockchain::import::check_spec_version;
 use codec::{Encode, Decode};
 use sha3::{Digest, Sha3_256};
 use std::convert::Infallible;
 
 const DIFFICULTY: usize = 2;
+const COUNTER_KEY: &[u8] = b"counter";
+const PARENT_KEY: &[u8] = b":parent";
+const SPEC_VERSION: u32 = 1;
 
 fn is_all_zero(arr: &[u8]) -> bool {
 	arr.iter().all(|i| *i == 0)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SealPolicy {
+	Mine,
+	NoProof,
+}
+
+impl Default for SealPolicy {
+	fn default() -> Self {
+		SealPolicy::Mine
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct UnsealedBlock {
 	parent_hash: Option<H256>,
@@ -20,15 +36,18 @@ pub struct UnsealedBlock {
 }
 
 impl UnsealedBlock {
-	pub fn seal(self) -> Block {
+	pub fn seal_with(self, policy: SealPolicy) -> Block {
 		let mut block = Block {
 			parent_hash: self.parent_hash,
 			extrinsics: self.extrinsics,
+			spec_version: SPEC_VERSION,
 			nonce: 0,
 		};
 
-		while !is_all_zero(&block.id()[0..DIFFICULTY]) {
-			block.nonce += 1;
+		if policy == SealPolicy::Mine {
+			while !is_all_zero(&block.id()[0..DIFFICULTY]) {
+				block.nonce += 1;
+			}
 		}
 
 		block
@@ -39,6 +58,7 @@ impl UnsealedBlock {
 pub struct Block {
 	parent_hash: Option<H256>,
 	extrinsics: Vec<Extrinsic>,
+	spec_version: u32,
 	nonce: u64,
 }
 
@@ -47,6 +67,7 @@ impl Block {
 		Block {
 			parent_hash: None,
 			extrinsics: Vec::new(),
+			spec_version: SPEC_VERSION,
 			nonce: 0,
 		}
 	}
@@ -59,6 +80,47 @@ impl BlockT for Block {
 		self.parent_hash
 	}
 
+	fn id(&self) -> H256 {
+		self.header().id()
+	}
+}
+
+impl VersionedBlock for Block {
+	fn spec_version(&self) -> u32 {
+		self.spec_version
+	}
+}
+
+impl HasHeader for Block {
+	type Header = Header;
+
+	fn header(&self) -> Header {
+		Header {
+			parent_hash: self.parent_hash,
+			extrinsics_root: H256::from_slice(
+				Sha3_256::digest(&self.extrinsics.encode()).as_slice()
+			),
+			spec_version: self.spec_version,
+			nonce: self.nonce,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Header {
+	parent_hash: Option<H256>,
+	extrinsics_root: H256,
+	spec_version: u32,
+	nonce: u64,
+}
+
+impl BlockT for Header {
+	type Identifier = H256;
+
+	fn parent_id(&self) -> Option<H256> {
+		self.parent_hash
+	}
+
 	fn id(&self) -> H256 {
 		H256::from_slice(Sha3_256::digest(&self.encode()).as_slice())
 	}
@@ -69,10 +131,16 @@ pub enum Extrinsic {
 	Add(u128),
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+	CounterChanged(u128),
+}
+
 #[derive(Debug)]
 pub enum Error {
 	Backend(Box<dyn std::error::Error>),
 	DifficultyTooLow,
+	IncompatibleSpecVersion,
 	StateCorruption,
 }
 
@@ -90,13 +158,19 @@ impl From<Error> for blockchain::import::Error {
 	}
 }
 
-#[derive(Clone)]
-pub struct Executor;
+#[derive(Clone, Default)]
+pub struct Executor {
+	seal_policy: SealPolicy,
+}
 
 impl Executor {
+	pub fn new(seal_policy: SealPolicy) -> Self {
+		Self { seal_policy }
+	}
+
 	fn read_counter(&self, state: &mut <Self as BlockExecutor>::Externalities) -> Result<u128, Error> {
 		Ok(
-			match state.read_storage(b"counter").expect("Error is infallible; qed") {
+			match state.read_storage(COUNTER_KEY).expect("Error is infallible; qed") {
 				Some(counter) => {
 					u128::decode(&mut counter.as_slice()).ok_or(Error::StateCorruption)?
 				},
@@ -106,7 +180,7 @@ impl Executor {
 	}
 
 	fn write_counter(&self, counter: u128, state: &mut <Self as BlockExecutor>::Externalities) {
-		state.write_storage(b"counter".to_vec(), counter.encode());
+		state.write_storage(COUNTER_KEY.to_vec(), counter.encode());
 	}
 }
 
@@ -114,31 +188,59 @@ impl BlockExecutor for Executor {
 	type Error = Error;
 	type Block = Block;
 	type Externalities = dyn StorageExternalities<Infallible> + 'static;
+	type Event = Event;
 
 	fn execute_block(
 		&self,
 		block: &Self::Block,
 		state: &mut Self::Externalities,
 	) -> Result<(), Error> {
-		if !is_all_zero(&block.id()[0..DIFFICULTY]) {
+		self.execute_block_with_events(block, state)?;
+
+		Ok(())
+	}
+
+	fn execute_block_with_events(
+		&self,
+		block: &Self::Block,
+		state: &mut Self::Externalities,
+	) -> Result<Vec<Event>, Error> {
+		check_spec_version(self, block)
+			.map_err(|_| Error::IncompatibleSpecVersion)?;
+
+		if self.seal_policy == SealPolicy::Mine && !is_all_zero(&block.id()[0..DIFFICULTY]) {
 			return Err(Error::DifficultyTooLow);
 		}
 
 		let mut counter = self.read_counter(state)?;
+		let mut events = Vec::new();
 
 		for extrinsic in &block.extrinsics {
 			match extrinsic {
 				Extrinsic::Add(add) => counter += add,
 			}
+			events.push(Event::CounterChanged(counter));
 		}
 
 		self.write_counter(counter, state);
 
-		Ok(())
+		Ok(events)
+	}
+
+	fn set_parent_context(&self, state: &mut Self::Externalities, parent_id: &H256) {
+		state.write_storage(PARENT_KEY.to_vec(), parent_id.as_bytes().to_vec());
+	}
+
+	fn spec_version(&self) -> u32 {
+		SPEC_VERSION
+	}
+
+	fn accessed_keys(&self, _block: &Self::Block) -> Option<Vec<Vec<u8>>> {
+		Some(vec![COUNTER_KEY.to_vec()])
 	}
 }
 
-impl SimpleBuilderExecutor for Executor {
+impl ExtrinsicBuilder for Executor {
 	type BuildBlock = UnsealedBlock;
 	type Extrinsic = Extrinsic;
 	type Inherent = ();
@@ -160,19 +262,20 @@ impl SimpleBuilderExecutor for Executor {
 		block: &mut Self::BuildBlock,
 		extrinsic: Self::Extrinsic,
 		state: &mut Self::Externalities,
-	) -> Result<(), Self::Error> {
+	) -> Result<Weight, Self::Error> {
 		let mut counter = self.read_counter(state)?;
 
-		match &extrinsic {
+		let weight = match &extrinsic {
 			Extrinsic::Add(add) => {
 				counter += add;
+				1
 			},
-		}
+		};
 
 		self.write_counter(counter, state);
 		block.extrinsics.push(extrinsic);
 
-		Ok(())
+		Ok(weight)
 	}
 
 	fn finalize_block(
@@ -183,3 +286,124 @@ impl SimpleBuilderExecutor for Executor {
 		Ok(())
 	}
 }
+
+impl Sealer for Executor {
+	type Unsealed = UnsealedBlock;
+	type Sealed = Block;
+
+	fn seal(&self, unsealed: UnsealedBlock) -> Block {
+		unsealed.seal_with(self.seal_policy)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use blockchain::AsExternalities;
+	use blockchain::backend::{SharedMemoryBackend, KeyValueMemoryState, ChainQuery};
+	use blockchain::import::BlockProducer;
+
+	type CounterBackend = SharedMemoryBackend<Block, (), KeyValueMemoryState>;
+
+	fn child_of(parent: &Block, extrinsics: Vec<Extrinsic>) -> Block {
+		UnsealedBlock {
+			parent_hash: Some(parent.id()),
+			extrinsics,
+		}.seal_with(SealPolicy::NoProof)
+	}
+
+	#[test]
+	fn header_id_matches_block_id() {
+		let genesis = Block::genesis();
+		let block = child_of(&genesis, vec![Extrinsic::Add(1)]);
+
+		for block in &[genesis, block] {
+			let header = block.header();
+			assert_eq!(header.id(), block.id());
+			assert_eq!(header.parent_id(), block.parent_id());
+		}
+	}
+
+	#[test]
+	fn each_add_yields_an_event() {
+		let executor = Executor::new(SealPolicy::NoProof);
+		let block = child_of(&Block::genesis(), vec![Extrinsic::Add(2), Extrinsic::Add(3)]);
+
+		let mut state = KeyValueMemoryState::default();
+		let events = executor.execute_block_with_events(&block, state.as_externalities()).unwrap();
+		assert_eq!(events, vec![Event::CounterChanged(2), Event::CounterChanged(5)]);
+	}
+
+	#[test]
+	fn accessed_keys_cover_executed_writes() {
+		let executor = Executor::new(SealPolicy::NoProof);
+		let block = child_of(&Block::genesis(), vec![Extrinsic::Add(2)]);
+		let keys = executor.accessed_keys(&block);
+		assert_eq!(keys, Some(vec![b"counter".to_vec()]));
+
+		let mut state = KeyValueMemoryState::default();
+		let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		executor.execute_block(&block, externalities).unwrap();
+		let written = externalities.iter_prefix(&[]).map(|(key, _)| key).collect::<Vec<_>>();
+		assert_eq!(Some(written), keys);
+	}
+
+	fn import_produced_blocks(
+		seal_policy: SealPolicy,
+		count: usize,
+	) -> CounterBackend {
+		let backend = CounterBackend::new_with_genesis(Block::genesis(), Default::default());
+		let producer = BlockProducer::new(Executor::new(seal_policy), backend.clone());
+
+		for _ in 0..count {
+			let (block, _) = producer.produce_sealed((), vec![Extrinsic::Add(2)]).unwrap();
+			assert!(backend.import_and_set_head(producer.executor(), block).unwrap());
+		}
+
+		backend
+	}
+
+	#[test]
+	fn unsealed_blocks_are_imported_without_proof() {
+		let backend = import_produced_blocks(SealPolicy::NoProof, 100);
+		assert_eq!(backend.depth_at(&backend.head()).unwrap(), 100);
+
+		let block = child_of(&Block::genesis(), Vec::new());
+		let mut state = KeyValueMemoryState::default();
+		match Executor::new(SealPolicy::Mine).execute_block(&block, state.as_externalities()) {
+			Err(Error::DifficultyTooLow) => (),
+			other => panic!("Expected difficulty too low, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn mined_blocks_have_valid_proof() {
+		let backend = import_produced_blocks(SealPolicy::Mine, 1);
+		assert_eq!(backend.depth_at(&backend.head()).unwrap(), 1);
+		assert!(is_all_zero(&backend.head()[0..DIFFICULTY]));
+	}
+
+	#[test]
+	fn imported_state_holds_parent_id() {
+		let backend = import_produced_blocks(SealPolicy::NoProof, 3);
+		let head = backend.block_at(&backend.head()).unwrap();
+		let parent_id = head.parent_id().unwrap();
+
+		let mut state = backend.state_at(&head.id()).unwrap();
+		let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		assert_eq!(externalities.read_storage(PARENT_KEY), Ok(Some(parent_id.as_bytes().to_vec())));
+	}
+
+	#[test]
+	fn block_sealed_through_trait_is_imported() {
+		let backend = CounterBackend::new_with_genesis(Block::genesis(), Default::default());
+		let executor = Executor::new(SealPolicy::NoProof);
+		let producer = BlockProducer::new(executor.clone(), backend.clone());
+
+		let (unsealed, _) = producer.produce_one((), vec![Extrinsic::Add(1)]).unwrap();
+		let block = Sealer::seal(&executor, unsealed);
+		let id = block.id();
+		assert!(backend.import_and_set_head(&executor, block).unwrap());
+		assert_eq!(backend.head(), id);
+	}
+}
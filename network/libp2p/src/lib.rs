@@ -1,7 +1,7 @@
 use core::fmt::Debug;
 use core::time::Duration;
 use core::ops::DerefMut;
-use parity_codec::{Encode, Decode};
+use core::marker::PhantomData;
 use libp2p::{identity, NetworkBehaviour, PeerId};
 use libp2p::mdns::Mdns;
 use libp2p::floodsub::{Floodsub, Topic, TopicBuilder};
@@ -10,14 +10,19 @@ use libp2p::core::swarm::{NetworkBehaviourEventProcess, NetworkBehaviourAction};
 use futures::{Async, stream::Stream};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Interval;
+use log::warn;
 use blockchain::backend::{SharedCommittable, ChainQuery, ImportLock};
 use blockchain::import::BlockImporter;
 use blockchain_network::{NetworkEnvironment, NetworkHandle, NetworkEvent};
 use blockchain_network::sync::{NetworkSyncMessage, NetworkSync, StatusProducer};
 
+pub use blockchain_network::codec::{MessageCodec, ParityCodec, MAX_MESSAGE_SIZE, encode_message, decode_message};
+#[cfg(feature = "json")]
+pub use blockchain_network::codec::JsonCodec;
+
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "(PeerId, NetworkSyncMessage<B, S>)", poll_method = "poll")]
-struct Behaviour<TSubstream: AsyncRead + AsyncWrite, B, S> {
+struct Behaviour<TSubstream: AsyncRead + AsyncWrite, B, S, C> {
 	floodsub: Floodsub<TSubstream>,
 	kademlia: Kademlia<TSubstream>,
 	mdns: Mdns<TSubstream>,
@@ -26,9 +31,13 @@ struct Behaviour<TSubstream: AsyncRead + AsyncWrite, B, S> {
 	topic: Topic,
 	#[behaviour(ignore)]
 	events: Vec<(PeerId, NetworkSyncMessage<B, S>)>,
+	#[behaviour(ignore)]
+	max_message_size: usize,
+	#[behaviour(ignore)]
+	_codec: PhantomData<C>,
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> Behaviour<TSubstream, B, S, C> {
 	fn poll<TEv>(&mut self) -> Async<NetworkBehaviourAction<TEv, (PeerId, NetworkSyncMessage<B, S>)>> {
 		if !self.events.is_empty() {
 			return Async::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)))
@@ -36,41 +45,54 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> Behaviour<TSubstream, B, S> {
 
 		Async::NotReady
 	}
+
+	fn publish(&mut self, message: NetworkSyncMessage<B, S>) where
+		C: MessageCodec<NetworkSyncMessage<B, S>>,
+	{
+		for data in encode_message::<C, _, _>(message, self.max_message_size) {
+			self.floodsub.publish(&self.topic, data);
+		}
+	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkEnvironment for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> NetworkEnvironment for Behaviour<TSubstream, B, S, C> {
 	type PeerId = PeerId;
 	type Message = NetworkSyncMessage<B, S>;
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkHandle for Behaviour<TSubstream, B, S>  where
-	B: Encode,
-	S: Encode,
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> NetworkHandle for Behaviour<TSubstream, B, S, C>  where
+	C: MessageCodec<NetworkSyncMessage<B, S>>,
 {
 	fn send(&mut self, _peer: &PeerId, message: NetworkSyncMessage<B, S>) {
-		self.floodsub.publish(&self.topic, message.encode());
+		self.publish(message);
 	}
 
 	fn broadcast(&mut self, message: NetworkSyncMessage<B, S>) {
-		self.floodsub.publish(&self.topic, message.encode());
+		self.publish(message);
 	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::floodsub::FloodsubEvent> for Behaviour<TSubstream, B, S> where
-	B: Encode + Decode + Debug,
-	S: Encode + Decode + Debug,
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> NetworkBehaviourEventProcess<libp2p::floodsub::FloodsubEvent> for Behaviour<TSubstream, B, S, C> where
+	B: Debug,
+	S: Debug,
+	C: MessageCodec<NetworkSyncMessage<B, S>>,
 {
 	fn inject_event(&mut self, floodsub_message: libp2p::floodsub::FloodsubEvent) {
 		if let libp2p::floodsub::FloodsubEvent::Message(floodsub_message) = floodsub_message {
-			let message = NetworkSyncMessage::<B, S>::decode(&mut &floodsub_message.data[..]).unwrap();
-
-			self.events.push((floodsub_message.source.clone(), message));
+			match decode_message::<C, _>(&floodsub_message.data, self.max_message_size) {
+				Some(message) => {
+					self.events.push((floodsub_message.source.clone(), message));
+				},
+				None => {
+					warn!("Dropped message from {:?}", floodsub_message.source);
+				},
+			}
 		}
 	}
 }
 
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::kad::KademliaOut> for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> NetworkBehaviourEventProcess<libp2p::kad::KademliaOut> for Behaviour<TSubstream, B, S, C> {
 	fn inject_event(&mut self, message: libp2p::kad::KademliaOut) {
 		if let libp2p::kad::KademliaOut::Discovered { peer_id, .. } = message {
 			println!("Discovered via Kademlia {:?}", peer_id);
@@ -79,7 +101,7 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp
 	}
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp2p::mdns::MdnsEvent> for Behaviour<TSubstream, B, S> {
+impl<TSubstream: AsyncRead + AsyncWrite, B, S, C> NetworkBehaviourEventProcess<libp2p::mdns::MdnsEvent> for Behaviour<TSubstream, B, S, C> {
     fn inject_event(&mut self, event: libp2p::mdns::MdnsEvent) {
         match event {
             libp2p::mdns::MdnsEvent::Discovered(list) => {
@@ -98,18 +120,20 @@ impl<TSubstream: AsyncRead + AsyncWrite, B, S> NetworkBehaviourEventProcess<libp
     }
 }
 
-pub fn start_network_simple_sync<Ba, I, St>(
+pub fn start_network_simple_sync<Ba, I, St, C>(
 	port: &str,
 	backend: Ba,
 	import_lock: ImportLock,
 	importer: I,
 	status: St,
+	max_message_size: usize,
 ) where
 	Ba: SharedCommittable + ChainQuery + Send + Sync + 'static,
-	Ba::Block: Debug + Encode + Decode + Send + Sync,
+	Ba::Block: Debug + Send + Sync,
 	I: BlockImporter<Block=Ba::Block> + Send + Sync + 'static,
 	St: StatusProducer + Send + Sync + 'static,
 	St::Status: Debug + Clone + Send + Sync,
+	C: MessageCodec<NetworkSyncMessage<Ba::Block, St::Status>> + Send + Sync + 'static,
 {
     // Create a random PeerId
     let local_key = identity::Keypair::generate_ed25519();
@@ -129,6 +153,8 @@ pub fn start_network_simple_sync<Ba, I, St>(
 
 			topic: topic.clone(),
 			events: Vec::new(),
+			max_message_size,
+			_codec: PhantomData,
 		};
 
 		assert!(behaviour.floodsub.subscribe(topic.clone()));
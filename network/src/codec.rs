@@ -0,0 +1,148 @@
+use parity_codec::{Encode, Decode};
+use log::warn;
+use crate::sync::NetworkSyncMessage;
+
+/// Default maximum size of a message to be published or decoded.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Wire format of sync messages.
+pub trait MessageCodec<M> {
+	/// Encode a message into bytes.
+	fn encode(message: &M) -> Vec<u8>;
+	/// Decode a message from bytes. Returns `None` if the data is invalid.
+	fn decode(data: &[u8]) -> Option<M>;
+}
+
+/// Message codec using parity codec.
+pub struct ParityCodec;
+
+impl<M: Encode + Decode> MessageCodec<M> for ParityCodec {
+	fn encode(message: &M) -> Vec<u8> {
+		message.encode()
+	}
+
+	fn decode(data: &[u8]) -> Option<M> {
+		M::decode(&mut &data[..])
+	}
+}
+
+/// Message codec using JSON.
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<M: serde::Serialize + serde::de::DeserializeOwned> MessageCodec<M> for JsonCodec {
+	fn encode(message: &M) -> Vec<u8> {
+		serde_json::to_vec(message).expect("Serializing into memory never fails; qed")
+	}
+
+	fn decode(data: &[u8]) -> Option<M> {
+		serde_json::from_slice(data).ok()
+	}
+}
+
+/// Encode a message to be published, into payloads of at most `max_size`
+/// bytes each. An oversized `BlockResponse` is split into several
+/// responses, while any other oversized message, including a single block
+/// too large on its own, is dropped.
+pub fn encode_message<C, B, S>(message: NetworkSyncMessage<B, S>, max_size: usize) -> Vec<Vec<u8>> where
+	C: MessageCodec<NetworkSyncMessage<B, S>>,
+{
+	let data = C::encode(&message);
+	if data.len() <= max_size {
+		return vec![data]
+	}
+
+	match message {
+		NetworkSyncMessage::BlockResponse { mut blocks } if blocks.len() > 1 => {
+			let rest = blocks.split_off(blocks.len() / 2);
+			let mut chunks = encode_message::<C, _, _>(
+				NetworkSyncMessage::BlockResponse { blocks }, max_size
+			);
+			chunks.extend(encode_message::<C, _, _>(
+				NetworkSyncMessage::BlockResponse { blocks: rest }, max_size
+			));
+			chunks
+		},
+		_ => {
+			warn!("Dropping oversized message ({} bytes)", data.len());
+			Vec::new()
+		},
+	}
+}
+
+/// Decode a message received from a peer. Oversized or invalid data is
+/// dropped, returning `None`, without attempting or failing the decode.
+pub fn decode_message<C: MessageCodec<M>, M>(data: &[u8], max_size: usize) -> Option<M> {
+	if data.len() > max_size {
+		warn!("Dropping oversized message ({} bytes)", data.len());
+		return None
+	}
+
+	let message = C::decode(data);
+	if message.is_none() {
+		warn!("Dropping invalid message ({} bytes)", data.len());
+	}
+	message
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type TestMessage = NetworkSyncMessage<Vec<u8>, u64>;
+
+	fn block_response() -> TestMessage {
+		NetworkSyncMessage::BlockResponse {
+			blocks: (0..5u8).map(|i| vec![i; i as usize + 1]).collect(),
+		}
+	}
+
+	fn assert_round_trip<C: MessageCodec<TestMessage>>() {
+		let message = block_response();
+		assert_eq!(C::decode(&C::encode(&message)), Some(message));
+	}
+
+	#[test]
+	fn parity_codec_round_trips_block_response() {
+		assert_round_trip::<ParityCodec>();
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn json_codec_round_trips_block_response() {
+		assert_round_trip::<JsonCodec>();
+	}
+
+	#[test]
+	fn invalid_messages_are_dropped() {
+		let encoded = ParityCodec::encode(&block_response());
+		let decode = |data: &[u8], max_size| decode_message::<ParityCodec, TestMessage>(data, max_size);
+
+		assert_eq!(decode(&encoded, encoded.len()), Some(block_response()));
+		assert_eq!(decode(&[0xff; 16], MAX_MESSAGE_SIZE), None);
+		assert_eq!(decode(&encoded[..encoded.len() / 2], MAX_MESSAGE_SIZE), None);
+		assert_eq!(decode(&encoded, encoded.len() - 1), None);
+	}
+
+	#[test]
+	fn oversized_block_response_is_split() {
+		let blocks = (0..10u8).map(|i| vec![i; 100]).collect::<Vec<_>>();
+		let message: TestMessage = NetworkSyncMessage::BlockResponse { blocks: blocks.clone() };
+		let chunks = encode_message::<ParityCodec, _, _>(message, 350);
+
+		assert!(chunks.len() > 1);
+		let mut decoded = Vec::new();
+		for chunk in chunks {
+			assert!(chunk.len() <= 350);
+			match decode_message::<ParityCodec, TestMessage>(&chunk, 350) {
+				Some(NetworkSyncMessage::BlockResponse { blocks }) => decoded.extend(blocks),
+				other => panic!("Unexpected chunk {:?}", other),
+			}
+		}
+		assert_eq!(decoded, blocks);
+
+		let single: TestMessage = NetworkSyncMessage::BlockResponse { blocks: vec![vec![0; 400]] };
+		assert!(encode_message::<ParityCodec, _, _>(single, 350).is_empty());
+	}
+}
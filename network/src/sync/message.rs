@@ -0,0 +1,20 @@
+use parity_codec::{Encode, Decode};
+#[cfg(feature = "json")]
+use serde::{Serialize, Deserialize};
+
+/// Message exchanged between peers while syncing.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum NetworkSyncMessage<B, S> {
+	/// Head status of the sender.
+	Status(S),
+	/// Request for blocks, starting at the given depth.
+	BlockRequest {
+		start_depth: u64,
+		count: u64,
+	},
+	/// Blocks sent in response to a request.
+	BlockResponse {
+		blocks: Vec<B>,
+	},
+}
@@ -1,34 +1,127 @@
 use core::task::{Context, Poll, Waker};
 use core::pin::Pin;
 use core::hash::Hash;
-use core::mem;
+use core::{cmp, mem};
+use core::fmt::{self, Write};
 use core::time::Duration;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use blockchain::Block;
 use blockchain::import::BlockImporter;
 use futures::{Stream, StreamExt};
 use futures_timer::Interval;
 use log::*;
 use rand::seq::IteratorRandom;
 
+mod strategy;
+mod message;
+
+pub use self::strategy::{RequestStrategy, RandomPeerStrategy, SyncStateView, PeerView};
+pub use self::message::NetworkSyncMessage;
+
+const HEIGHT_WINDOW: usize = 16;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerMetadata {
+	pub protocol_version: Option<u32>,
+	pub advertised_height: Option<usize>,
+	pub address: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo<P> {
+	pub peer: P,
+	pub metadata: PeerMetadata,
+	pub is_compatible: bool,
+}
+
+/// Snapshot of sync progress, for metrics and progress reporting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncProgress {
+	pub height: Option<usize>,
+	pub best_peer_height: Option<usize>,
+	pub blocks_remaining: Option<usize>,
+	pub ticks_remaining: Option<usize>,
+	pub is_major_syncing: bool,
+}
+
 pub struct PeerStatus<H> {
 	head_status: Option<(H, usize)>,
+	checksum: Option<[u8; 32]>,
 	pending_request: Option<usize>,
+	metadata: PeerMetadata,
+	connected: usize,
+}
+
+/// Last known status of a recently disconnected peer, kept for
+/// `SyncConfig::rejoin_ttl` ticks so that a quick reconnection does not have
+/// to rediscover it.
+struct DisconnectedPeer<H> {
+	head_status: Option<(H, usize)>,
+	advertised_height: Option<usize>,
+	tick: usize,
 }
 
 impl<H> Default for PeerStatus<H> {
 	fn default() -> Self {
 		Self {
 			head_status: None,
+			checksum: None,
 			pending_request: None,
+			metadata: Default::default(),
+			connected: 0,
 		}
 	}
 }
 
-#[derive(PartialEq, Eq)]
-pub enum SyncEvent<P> {
+impl<H> PeerStatus<H> {
+	fn new(connected: usize) -> Self {
+		Self {
+			connected,
+			..Default::default()
+		}
+	}
+
+	/// Whether the peer has not reported its status nor connected for
+	/// `stale_ticks` ticks, and has a request outstanding. A status restored
+	/// on rejoin is older than the connection, which then counts instead.
+	fn is_stale(&self, current_tick: usize, stale_ticks: usize) -> bool {
+		let last_seen = self.head_status.as_ref()
+			.map(|h| cmp::max(h.1, self.connected))
+			.unwrap_or(self.connected);
+		self.pending_request.is_some() && current_tick - last_seen >= stale_ticks
+	}
+
+	fn is_in_agreement(&self, checksum: Option<&[u8; 32]>) -> bool {
+		checksum.is_some() && self.checksum.as_ref() == checksum
+	}
+
+	fn is_compatible(&self, protocol_version: u32) -> bool {
+		self.metadata.protocol_version
+			.map(|v| v == protocol_version)
+			.unwrap_or(true)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SyncEvent<P, Id> {
 	QueryStatus,
 	QueryPeerStatus(P),
 	QueryBlocks(P),
+	QueryBlock(P, Id),
+	PendingBlocksFull,
+	PeerTimedOut(P),
+	Reorg { retracted: Vec<Id>, enacted: Vec<Id> },
+}
+
+/// Head status with a total order, comparing depth first, then head
+/// hash. Distinct heads at the same depth never compare equal, so that
+/// peers on competing forks of equal height still request blocks from
+/// each other and converge.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompositeStatus<Id> {
+	pub depth: usize,
+	pub head: Id,
 }
 
 #[derive(PartialEq, Eq)]
@@ -36,73 +129,455 @@ pub struct SyncConfig {
 	pub peer_update_frequency: usize,
 	pub update_frequency: usize,
 	pub request_timeout: usize,
+	pub protocol_version: u32,
+	pub max_pending_blocks: usize,
+	pub min_peers_to_sync: usize,
+	pub rejoin_ttl: usize,
+	pub major_sync_threshold: usize,
+	pub peer_stale_ticks: usize,
+	pub max_imports_per_poll: usize,
+}
+
+/// Presets are in ticks, and use protocol version 0. Set `protocol_version`
+/// with struct update syntax when peers advertise one.
+impl SyncConfig {
+	/// Preset for peers on the same machine or LAN. Queries every tick and
+	/// times out quickly, trading bandwidth for latency.
+	pub fn fast_local() -> Self {
+		Self {
+			peer_update_frequency: 1,
+			update_frequency: 1,
+			request_timeout: 2,
+			protocol_version: 0,
+			max_pending_blocks: 1024,
+			min_peers_to_sync: 1,
+			rejoin_ttl: 10,
+			major_sync_threshold: 64,
+			peer_stale_ticks: 20,
+			max_imports_per_poll: 1024,
+		}
+	}
+
+	/// Preset for peers over the internet. Tolerates moderate latency
+	/// without flooding peers with status queries.
+	pub fn wan_default() -> Self {
+		Self {
+			peer_update_frequency: 5,
+			update_frequency: 5,
+			request_timeout: 10,
+			protocol_version: 0,
+			max_pending_blocks: 4096,
+			min_peers_to_sync: 1,
+			rejoin_ttl: 30,
+			major_sync_threshold: 64,
+			peer_stale_ticks: 60,
+			max_imports_per_poll: 512,
+		}
+	}
+
+	/// Preset for constrained links. Queries rarely and buffers few
+	/// blocks, at the cost of slower sync.
+	pub fn low_bandwidth() -> Self {
+		Self {
+			peer_update_frequency: 20,
+			update_frequency: 20,
+			request_timeout: 40,
+			protocol_version: 0,
+			max_pending_blocks: 256,
+			min_peers_to_sync: 1,
+			rejoin_ttl: 60,
+			major_sync_threshold: 64,
+			peer_stale_ticks: 120,
+			max_imports_per_poll: 64,
+		}
+	}
+
+	/// Whether the config can drive sync. Frequencies must be non-zero, the
+	/// request timeout must be at least the peer update frequency, peers
+	/// must not go stale before a request can time out, and at least one
+	/// pending block and one import per poll must be allowed.
+	pub fn is_valid(&self) -> bool {
+		self.peer_update_frequency > 0 &&
+			self.update_frequency > 0 &&
+			self.request_timeout >= self.peer_update_frequency &&
+			self.peer_stale_ticks > self.request_timeout &&
+			self.max_pending_blocks > 0 &&
+			self.max_imports_per_poll > 0
+	}
 }
 
+/// Network sync state machine.
+///
+/// Head statuses `H` are compared with `PartialOrd`. A peer is considered
+/// ahead of us only when its status compares strictly greater than ours.
+/// Statuses that compare equal or are incomparable never trigger a block
+/// request, so `H` should distinguish competing heads, as
+/// `CompositeStatus` does.
 pub struct NetworkSync<P, H, I: BlockImporter> {
 	head_status: (H, usize),
+	checksum: Option<[u8; 32]>,
 	tick: usize,
 	peers: HashMap<P, PeerStatus<H>>,
-	pending_blocks: Vec<I::Block>,
+	disconnected_peers: HashMap<P, DisconnectedPeer<H>>,
+	pending_blocks: VecDeque<I::Block>,
+	dropped_blocks: usize,
+	blacklisted_blocks: HashSet<<I::Block as Block>::Identifier>,
+	import_pass_remaining: usize,
+	import_progress: bool,
 	importer: I,
 	waker: Option<Waker>,
-	timer: Interval,
-	pending_events: VecDeque<SyncEvent<P>>,
+	timer: Option<Interval>,
+	pending_events: VecDeque<SyncEvent<P, <I::Block as Block>::Identifier>>,
+	queued_events: HashSet<SyncEvent<P, <I::Block as Block>::Identifier>>,
 	last_sync: Option<usize>,
+	heights: VecDeque<(usize, usize)>,
 	config: SyncConfig,
+	strategy: Box<dyn RequestStrategy<P, H, <I::Block as Block>::Identifier> + Send>,
+	status_height: Option<StatusHeight<H>>,
 }
 
+type StatusHeight<H> = Box<dyn Fn(&H) -> usize + Send>;
+
 impl<P, H, I> NetworkSync<P, H, I> where
 	I: BlockImporter,
 	P: PartialEq + Eq + Hash,
 	H: PartialOrd,
 {
-	pub fn new(head: H, importer: I, tick_duration: Duration, config: SyncConfig) -> Self {
+	pub fn new(head: H, importer: I, tick_duration: Duration, config: SyncConfig) -> Self where
+		P: Clone,
+	{
+		Self {
+			timer: Some(Interval::new(tick_duration)),
+			..Self::new_without_timer(head, importer, config)
+		}
+	}
+
+	/// Create a sync state machine that is only advanced by `tick_now`.
+	pub fn new_without_timer(head: H, importer: I, config: SyncConfig) -> Self where
+		P: Clone,
+	{
 		Self {
 			head_status: (head, 0),
+			checksum: None,
 			tick: 0,
 			peers: HashMap::new(),
-			pending_blocks: Vec::new(),
+			disconnected_peers: HashMap::new(),
+			pending_blocks: VecDeque::new(),
+			dropped_blocks: 0,
+			blacklisted_blocks: HashSet::new(),
+			import_pass_remaining: 0,
+			import_progress: true,
 			importer,
 			waker: None,
-			timer: Interval::new(tick_duration),
+			timer: None,
 			pending_events: VecDeque::new(),
+			queued_events: HashSet::new(),
 			last_sync: None,
+			heights: VecDeque::new(),
 			config,
+			strategy: Box::new(RandomPeerStrategy),
+			status_height: None,
+		}
+	}
+
+	/// Replace the strategy deciding when, and from whom, to request blocks.
+	pub fn with_request_strategy<R>(self, strategy: R) -> Self where
+		R: RequestStrategy<P, H, <I::Block as Block>::Identifier> + Send + 'static,
+	{
+		Self {
+			strategy: Box::new(strategy),
+			..self
 		}
 	}
 
-	pub fn note_blocks(&mut self, mut blocks: Vec<I::Block>, _source: Option<P>) {
-		self.pending_blocks.append(&mut blocks);
+	/// Derive our height from our head status, so that `note_status`
+	/// records it as `note_height` would.
+	pub fn with_status_height<F>(mut self, status_height: F) -> Self where
+		F: Fn(&H) -> usize + Send + 'static,
+	{
+		let height = status_height(&self.head_status.0);
+		self.note_height(height);
+		self.status_height = Some(Box::new(status_height));
+		self
+	}
+
+	pub fn note_blocks(&mut self, mut blocks: Vec<I::Block>, _source: Option<P>) where
+		P: Clone,
+	{
+		if !self.blacklisted_blocks.is_empty() {
+			let blacklisted_blocks = &mut self.blacklisted_blocks;
+			blocks.retain(|block| {
+				let blacklisted = blacklisted_blocks.contains(&block.id()) ||
+					block.parent_id().map(|parent_id| blacklisted_blocks.contains(&parent_id))
+						.unwrap_or(false);
+				if blacklisted {
+					trace!("Dropping blacklisted block");
+					blacklisted_blocks.insert(block.id());
+				}
+				!blacklisted
+			});
+		}
+
+		let capacity = self.config.max_pending_blocks.saturating_sub(self.pending_blocks.len());
+		if blocks.len() > capacity {
+			let dropped = blocks.len() - capacity;
+			warn!("Pending blocks full, dropping {} blocks", dropped);
+			blocks.truncate(capacity);
+			self.dropped_blocks += dropped;
+			self.push_event(SyncEvent::PendingBlocksFull);
+		}
+
+		if !blocks.is_empty() {
+			// New blocks may be the parents of blocks that failed to
+			// import, so they warrant another pass.
+			self.import_progress = true;
+		}
+		self.pending_blocks.extend(blocks);
 		self.wake();
 	}
 
+	pub fn dropped_blocks(&self) -> usize {
+		self.dropped_blocks
+	}
+
+	/// Refuse to import a block, and any of its descendants, from now on.
+	/// Pending copies of the block are dropped.
+	pub fn blacklist_block(&mut self, id: <I::Block as Block>::Identifier) {
+		self.pending_blocks.retain(|block| block.id() != id);
+		self.import_pass_remaining = 0;
+		self.import_progress = true;
+		self.blacklisted_blocks.insert(id);
+	}
+
+	pub fn is_blacklisted(&self, id: &<I::Block as Block>::Identifier) -> bool {
+		self.blacklisted_blocks.contains(id)
+	}
+
 	pub fn note_peer_status(&mut self, peer: P, status: H) {
-		self.peers.entry(peer)
-			.or_insert(Default::default())
-			.head_status = Some((status, self.tick));
+		let tick = self.tick;
+		let peer_status = self.peers.entry(peer)
+			.or_insert_with(|| PeerStatus::new(tick));
+		peer_status.head_status = Some((status, tick));
+		peer_status.checksum = None;
 	}
 
 	pub fn note_status(&mut self, status: H) {
+		if let Some(height) = self.status_height.as_ref().map(|f| f(&status)) {
+			self.note_height(height);
+		}
 		self.head_status = (status, self.tick);
+		self.checksum = None;
+	}
+
+	/// Note the checksum of our canonical chain, as computed by
+	/// `ChainQuery::canon_checksum`. It is cleared by the next
+	/// `note_status`, until noted again.
+	pub fn note_checksum(&mut self, checksum: [u8; 32]) {
+		self.checksum = Some(checksum);
+	}
+
+	/// Note the canonical chain checksum a peer sent along with its
+	/// status. It is cleared by the next `note_peer_status` for the peer.
+	pub fn note_peer_checksum(&mut self, peer: P, checksum: [u8; 32]) {
+		let tick = self.tick;
+		self.peers.entry(peer)
+			.or_insert_with(|| PeerStatus::new(tick))
+			.checksum = Some(checksum);
+	}
+
+	/// Whether the peer's canonical chain is known to equal ours. Peers in
+	/// agreement are never considered ahead of us.
+	pub fn is_in_agreement(&self, peer: &P) -> bool {
+		self.peers.get(peer)
+			.map(|status| status.is_in_agreement(self.checksum.as_ref()))
+			.unwrap_or(false)
+	}
+
+	pub fn note_height(&mut self, height: usize) {
+		if self.heights.back().map(|(tick, _)| *tick == self.tick).unwrap_or(false) {
+			self.heights.pop_back();
+		}
+
+		self.heights.push_back((self.tick, height));
+		while self.heights.len() > HEIGHT_WINDOW {
+			self.heights.pop_front();
+		}
+	}
+
+	pub fn note_peer_height(&mut self, peer: P, height: usize) {
+		let tick = self.tick;
+		self.peers.entry(peer)
+			.or_insert_with(|| PeerStatus::new(tick))
+			.metadata.advertised_height = Some(height);
+	}
+
+	pub fn height(&self) -> Option<usize> {
+		self.heights.back().map(|(_, height)| *height)
+	}
+
+	pub fn best_peer_height(&self) -> Option<usize> {
+		self.peers.values()
+			.filter(|status| status.is_compatible(self.config.protocol_version))
+			.filter_map(|status| status.metadata.advertised_height)
+			.max()
+	}
+
+	pub fn estimated_blocks_remaining(&self) -> Option<usize> {
+		let height = self.height()?;
+		let best_peer_height = self.best_peer_height()?;
+
+		Some(best_peer_height.saturating_sub(height))
+	}
+
+	/// Whether we are more than `SyncConfig::major_sync_threshold` blocks
+	/// behind the best advertised peer height, as opposed to catching up a
+	/// few blocks.
+	pub fn is_major_syncing(&self) -> bool {
+		self.estimated_blocks_remaining()
+			.map(|remaining| remaining > self.config.major_sync_threshold)
+			.unwrap_or(false)
+	}
+
+	pub fn estimated_ticks_remaining(&self) -> Option<usize> {
+		let remaining = self.estimated_blocks_remaining()?;
+		if remaining == 0 {
+			return Some(0)
+		}
+
+		let (first_tick, first_height) = self.heights.front()?;
+		let (last_tick, last_height) = self.heights.back()?;
+		let blocks = last_height.saturating_sub(*first_height);
+		let ticks = last_tick - first_tick;
+		if blocks == 0 || ticks == 0 {
+			return None
+		}
+
+		Some((remaining * ticks - 1) / blocks + 1)
+	}
+
+	pub fn progress(&self) -> SyncProgress {
+		SyncProgress {
+			height: self.height(),
+			best_peer_height: self.best_peer_height(),
+			blocks_remaining: self.estimated_blocks_remaining(),
+			ticks_remaining: self.estimated_ticks_remaining(),
+			is_major_syncing: self.is_major_syncing(),
+		}
 	}
 
 	pub fn note_connected(&mut self, peer: P) {
-		self.peers.insert(peer, Default::default());
+		self.note_connected_with_metadata(peer, Default::default());
+	}
+
+	pub fn note_connected_with_metadata(&mut self, peer: P, mut metadata: PeerMetadata) {
+		let mut head_status = None;
+		if let Some(disconnected) = self.disconnected_peers.remove(&peer) {
+			if self.tick - disconnected.tick <= self.config.rejoin_ttl {
+				head_status = disconnected.head_status;
+				metadata.advertised_height = metadata.advertised_height
+					.or(disconnected.advertised_height);
+			}
+		}
+
+		self.peers.insert(peer, PeerStatus {
+			head_status,
+			metadata,
+			..PeerStatus::new(self.tick)
+		});
+	}
+
+	pub fn peers_info(&self) -> Vec<PeerInfo<P>> where
+		P: Clone,
+	{
+		self.peers.iter().map(|(peer, status)| PeerInfo {
+			peer: peer.clone(),
+			metadata: status.metadata.clone(),
+			is_compatible: status.is_compatible(self.config.protocol_version),
+		}).collect()
 	}
 
 	pub fn note_disconnected(&mut self, peer: P) {
-		self.peers.remove(&peer);
+		if let Some(status) = self.peers.remove(&peer) {
+			if status.head_status.is_some() || status.metadata.advertised_height.is_some() {
+				self.disconnected_peers.insert(peer, DisconnectedPeer {
+					head_status: status.head_status,
+					advertised_height: status.metadata.advertised_height,
+					tick: self.tick,
+				});
+			}
+		}
+	}
+
+	/// Human-readable snapshot of the sync state, for debugging stuck
+	/// syncs.
+	pub fn debug_dump(&self) -> String where
+		P: fmt::Debug,
+	{
+		let mut dump = String::new();
+		let _ = writeln!(dump, "height: {:?}", self.height());
+		let _ = writeln!(dump, "tick: {}", self.tick);
+		let _ = writeln!(dump, "last_sync: {:?}", self.last_sync);
+		let _ = writeln!(dump, "pending_blocks: {}", self.pending_blocks.len());
+		let _ = writeln!(dump, "dropped_blocks: {}", self.dropped_blocks);
+		let _ = writeln!(dump, "peers: {}", self.peers.len());
+		for (peer, status) in &self.peers {
+			let _ = writeln!(
+				dump,
+				"  {:?}: height: {:?}, status_age: {:?}, request_age: {:?}",
+				peer,
+				status.metadata.advertised_height,
+				status.head_status.as_ref().map(|h| self.tick - h.1),
+				status.pending_request.map(|tick| self.tick - tick),
+			);
+		}
+		dump
+	}
+
+	pub fn peer_count(&self) -> usize {
+		self.peers.len()
+	}
+
+	pub fn connected_peers(&self) -> impl Iterator<Item=&P> {
+		self.peers.keys()
 	}
 
 	pub fn is_syncing(&self) -> bool {
+		let mut ahead_peers = 0;
 		for (_, peer_status) in &self.peers {
+			if !peer_status.is_compatible(self.config.protocol_version) ||
+				peer_status.is_in_agreement(self.checksum.as_ref())
+			{
+				continue
+			}
+
 			if let Some(peer_head_status) = peer_status.head_status.as_ref() {
 				if peer_head_status.0 > self.head_status.0 {
-					return true
+					ahead_peers += 1;
 				}
 			}
 		}
-		false
+		ahead_peers >= cmp::max(self.config.min_peers_to_sync, 1)
+	}
+
+	pub fn request_block(&mut self, id: <I::Block as Block>::Identifier) -> bool where
+		P: Clone,
+	{
+		let protocol_version = self.config.protocol_version;
+		let current_tick = self.tick;
+		let peer = match self.peers.iter_mut()
+			.filter(|(_, status)| status.is_compatible(protocol_version))
+			.choose(&mut rand::thread_rng())
+		{
+			Some((peer, status)) => {
+				status.pending_request = Some(current_tick);
+				peer.clone()
+			},
+			None => return false,
+		};
+
+		self.push_event(SyncEvent::QueryBlock(peer, id));
+		self.wake();
+		true
 	}
 
 	fn wake(&mut self) {
@@ -111,80 +586,136 @@ impl<P, H, I> NetworkSync<P, H, I> where
 		}
 	}
 
-	fn push_event(&mut self, event: SyncEvent<P>) {
-		if !self.pending_events.contains(&event) {
+	fn push_event(&mut self, event: SyncEvent<P, <I::Block as Block>::Identifier>) where
+		P: Clone,
+	{
+		if self.queued_events.insert(event.clone()) {
 			self.pending_events.push_back(event);
 		}
 	}
 }
 
-impl<P, H, I> Stream for NetworkSync<P, H, I> where
-	P: PartialEq + Eq + Hash + Clone + Unpin,
-	H: PartialOrd + Unpin,
-	I: BlockImporter + Unpin,
-	I::Block: Clone + Unpin,
+impl<P, H, I> NetworkSync<P, H, I> where
+	P: PartialEq + Eq + Hash + Clone,
+	H: PartialOrd,
+	I: BlockImporter,
+	I::Block: Clone,
 	I::Error: core::fmt::Debug,
 {
-	type Item = SyncEvent<P>;
+	/// Advance one tick and re-evaluate sync events immediately, without
+	/// waiting for the timer. Returns whether pending blocks remain to be
+	/// tried, in which case `import_now` continues importing them.
+	pub fn tick_now(&mut self) -> bool {
+		self.tick += 1;
+		let remaining = self.import_pending_blocks();
+		self.update_events();
+		remaining
+	}
 
-	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-		self.waker = Some(cx.waker().clone());
+	/// Try importing pending blocks, within the same bound as a poll,
+	/// without advancing the tick. Returns whether pending blocks remain
+	/// to be tried.
+	pub fn import_now(&mut self) -> bool {
+		self.import_pending_blocks()
+	}
 
-		let mut pending_blocks = Vec::new();
-		mem::swap(&mut self.pending_blocks, &mut pending_blocks);
-		let mut pending_blocks = pending_blocks.into_iter().map(|v| Some(v)).collect::<Vec<_>>();
+	/// Take the next queued sync event, if any.
+	pub fn pop_event(&mut self) -> Option<SyncEvent<P, <I::Block as Block>::Identifier>> {
+		let event = self.pending_events.pop_front()?;
+		self.queued_events.remove(&event);
+		Some(event)
+	}
+
+	/// Try importing pending blocks, making at most
+	/// `SyncConfig::max_imports_per_poll` import attempts. Blocks are
+	/// retried in passes until a pass imports nothing, after which the
+	/// remaining blocks are discarded. Blocks failing to import are moved to
+	/// the back of the queue for the next pass. Passes resume across calls.
+	/// Returns whether pending blocks remain to be tried.
+	fn import_pending_blocks(&mut self) -> bool {
+		let mut budget = self.config.max_imports_per_poll;
 
 		loop {
-			let mut progress = false;
-
-			for block in &mut pending_blocks {
-				if let Some(sblock) = block {
-					match self.importer.import_block(sblock.clone()) {
-						Ok(()) => {
-							*block = None;
-							progress = true;
-							trace!("Imported one block");
-						},
-						Err(e) => {
-							warn!("Import block failed: {:?}", e);
-						},
-					}
+			if self.import_pass_remaining == 0 {
+				if self.pending_blocks.is_empty() {
+					return false
 				}
+
+				if !mem::replace(&mut self.import_progress, false) {
+					warn!("{} blocks cannot be imported", self.pending_blocks.len());
+					self.pending_blocks.clear();
+					return false
+				}
+
+				self.import_pass_remaining = self.pending_blocks.len();
 			}
 
-			if !progress {
-				break
+			if budget == 0 {
+				return true
 			}
-		}
+			budget -= 1;
+			self.import_pass_remaining -= 1;
 
-		let unimported_blocks = pending_blocks.iter().filter(|v| v.is_some()).count();
-		if unimported_blocks != 0 {
-			warn!("{} blocks cannot be imported", unimported_blocks);
-		}
+			let block = match self.pending_blocks.pop_front() {
+				Some(block) => block,
+				None => {
+					self.import_pass_remaining = 0;
+					continue
+				},
+			};
+			match self.importer.import_block_with_route(block.clone()) {
+				Ok(route) => {
+					self.import_progress = true;
+					trace!("Imported one block");
 
-		loop {
-			match self.timer.poll_next_unpin(cx) {
-				Poll::Pending => break,
-				Poll::Ready(Some(())) => {
-					self.tick += 1;
+					if let Some(route) = route.filter(|r| !r.retracted().is_empty()) {
+						self.push_event(SyncEvent::Reorg {
+							retracted: route.retracted().to_vec(),
+							enacted: route.enacted().to_vec(),
+						});
+					}
 				},
-				Poll::Ready(None) => {
-					return Poll::Ready(None)
+				Err(e) => {
+					warn!("Import block failed: {:?}", e);
+					self.pending_blocks.push_back(block);
 				},
 			}
 		}
+	}
 
+	fn update_events(&mut self) {
 		let mut new_events = Vec::new();
 		let current_tick = self.tick;
+		let rejoin_ttl = self.config.rejoin_ttl;
+
+		self.disconnected_peers.retain(|_, disconnected| {
+			current_tick - disconnected.tick <= rejoin_ttl
+		});
+
 		let request_timeout = self.config.request_timeout;
-		let update_frequency = self.config.update_frequency;
 		let peer_update_frequency = self.config.peer_update_frequency;
+		let protocol_version = self.config.protocol_version;
 
 		if self.tick - self.head_status.1 >= self.config.update_frequency {
 			new_events.push(SyncEvent::QueryStatus);
 		}
 
+		let peer_stale_ticks = self.config.peer_stale_ticks;
+		self.peers.retain(|peer, status| {
+			if status.is_stale(current_tick, peer_stale_ticks) {
+				warn!("Peer timed out, evicting");
+				new_events.push(SyncEvent::PeerTimedOut(peer.clone()));
+				false
+			} else {
+				true
+			}
+		});
+
 		for (peer, status) in &mut self.peers {
+			if !status.is_compatible(protocol_version) {
+				continue
+			}
+
 			if let Some(last_tick) = status.pending_request {
 				if current_tick - last_tick >= request_timeout {
 					status.pending_request = None;
@@ -200,37 +731,763 @@ impl<P, H, I> Stream for NetworkSync<P, H, I> where
 			}
 		}
 
-		if self.is_syncing() {
-			let mut need_initialize_new_request = false;
+		let view = SyncStateView {
+			head_status: &self.head_status.0,
+			tick: current_tick,
+			last_sync: self.last_sync,
+			is_syncing: self.is_syncing(),
+			config: &self.config,
+			peers: self.peers.iter().map(|(peer, status)| PeerView {
+				peer,
+				head_status: status.head_status.as_ref().map(|h| &h.0),
+				is_compatible: status.is_compatible(protocol_version),
+			}).collect(),
+		};
+		let requests = self.strategy.next_requests(&view);
 
-			for (_, status) in &self.peers {
-				if status.head_status.as_ref().map(|h| h.0 > self.head_status.0).unwrap_or(false) {
-					need_initialize_new_request = true;
-				}
+		for request in &requests {
+			let peer = match request {
+				SyncEvent::QueryBlocks(peer) | SyncEvent::QueryBlock(peer, _) => peer,
+				_ => continue,
+			};
+
+			if let Some(status) = self.peers.get_mut(peer) {
+				status.pending_request = Some(current_tick);
 			}
+			self.last_sync = Some(current_tick);
+		}
+		new_events.extend(requests);
+
+		for event in new_events {
+			self.push_event(event);
+		}
+	}
+}
 
-			need_initialize_new_request = need_initialize_new_request && self.last_sync.map(|l| {
-				current_tick - l >= update_frequency
-			}).unwrap_or(true);
+impl<P, H, I> Stream for NetworkSync<P, H, I> where
+	P: PartialEq + Eq + Hash + Clone + Unpin,
+	H: PartialOrd + Unpin,
+	I: BlockImporter + Unpin,
+	I::Block: Clone + Unpin,
+	<I::Block as Block>::Identifier: Unpin,
+	I::Error: core::fmt::Debug,
+{
+	type Item = SyncEvent<P, <I::Block as Block>::Identifier>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		self.waker = Some(cx.waker().clone());
+
+		if self.import_pending_blocks() {
+			// Yield to the executor, and continue importing on the next
+			// poll.
+			cx.waker().wake_by_ref();
+		}
+
+		while let Some(timer) = self.timer.as_mut() {
+			match timer.poll_next_unpin(cx) {
+				Poll::Pending => break,
+				Poll::Ready(Some(())) => {
+					self.tick += 1;
+				},
+				Poll::Ready(None) => {
+					return Poll::Ready(None)
+				},
+			}
+		}
+
+		self.update_events();
+
+		match self.pop_event() {
+			Some(event) => Poll::Ready(Some(event)),
+			None => Poll::Pending,
+		}
+	}
+}
+
+/// Network sync that can be shared across threads.
+///
+/// The wrapped importer and block type must be `Send + Sync + 'static`
+/// for the shared sync to be sent to networking threads.
+pub struct SharedNetworkSync<P, H, I: BlockImporter>(Arc<Mutex<NetworkSync<P, H, I>>>);
+
+impl<P, H, I: BlockImporter> Clone for SharedNetworkSync<P, H, I> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+
+impl<P, H, I> SharedNetworkSync<P, H, I> where
+	I: BlockImporter,
+	P: PartialEq + Eq + Hash,
+	H: PartialOrd,
+{
+	pub fn new(sync: NetworkSync<P, H, I>) -> Self {
+		Self(Arc::new(Mutex::new(sync)))
+	}
 
-			if need_initialize_new_request {
-				if let Some((peer, status)) = self.peers.iter_mut().choose(&mut rand::thread_rng()) {
-					new_events.push(SyncEvent::QueryBlocks(peer.clone()));
-					status.pending_request = Some(current_tick);
+	pub fn note_blocks(&self, blocks: Vec<I::Block>, source: Option<P>) where
+		P: Clone,
+	{
+		self.0.lock().expect("Lock is poisoned").note_blocks(blocks, source)
+	}
+
+	pub fn dropped_blocks(&self) -> usize {
+		self.0.lock().expect("Lock is poisoned").dropped_blocks()
+	}
+
+	pub fn debug_dump(&self) -> String where
+		P: fmt::Debug,
+	{
+		self.0.lock().expect("Lock is poisoned").debug_dump()
+	}
+
+	pub fn blacklist_block(&self, id: <I::Block as Block>::Identifier) {
+		self.0.lock().expect("Lock is poisoned").blacklist_block(id)
+	}
+
+	pub fn is_blacklisted(&self, id: &<I::Block as Block>::Identifier) -> bool {
+		self.0.lock().expect("Lock is poisoned").is_blacklisted(id)
+	}
+
+	pub fn note_peer_status(&self, peer: P, status: H) {
+		self.0.lock().expect("Lock is poisoned").note_peer_status(peer, status)
+	}
+
+	pub fn note_status(&self, status: H) {
+		self.0.lock().expect("Lock is poisoned").note_status(status)
+	}
+
+	pub fn note_checksum(&self, checksum: [u8; 32]) {
+		self.0.lock().expect("Lock is poisoned").note_checksum(checksum)
+	}
+
+	pub fn note_peer_checksum(&self, peer: P, checksum: [u8; 32]) {
+		self.0.lock().expect("Lock is poisoned").note_peer_checksum(peer, checksum)
+	}
+
+	pub fn is_in_agreement(&self, peer: &P) -> bool {
+		self.0.lock().expect("Lock is poisoned").is_in_agreement(peer)
+	}
+
+	pub fn note_connected(&self, peer: P) {
+		self.0.lock().expect("Lock is poisoned").note_connected(peer)
+	}
+
+	pub fn note_connected_with_metadata(&self, peer: P, metadata: PeerMetadata) {
+		self.0.lock().expect("Lock is poisoned").note_connected_with_metadata(peer, metadata)
+	}
+
+	pub fn peers_info(&self) -> Vec<PeerInfo<P>> where
+		P: Clone,
+	{
+		self.0.lock().expect("Lock is poisoned").peers_info()
+	}
+
+	pub fn note_disconnected(&self, peer: P) {
+		self.0.lock().expect("Lock is poisoned").note_disconnected(peer)
+	}
+
+	pub fn peer_count(&self) -> usize {
+		self.0.lock().expect("Lock is poisoned").peer_count()
+	}
+
+	pub fn connected_peers(&self) -> Vec<P> where
+		P: Clone,
+	{
+		self.0.lock().expect("Lock is poisoned").connected_peers().cloned().collect()
+	}
+
+	pub fn request_block(&self, id: <I::Block as Block>::Identifier) -> bool where
+		P: Clone,
+	{
+		self.0.lock().expect("Lock is poisoned").request_block(id)
+	}
+
+	pub fn note_height(&self, height: usize) {
+		self.0.lock().expect("Lock is poisoned").note_height(height)
+	}
+
+	pub fn note_peer_height(&self, peer: P, height: usize) {
+		self.0.lock().expect("Lock is poisoned").note_peer_height(peer, height)
+	}
+
+	pub fn estimated_blocks_remaining(&self) -> Option<usize> {
+		self.0.lock().expect("Lock is poisoned").estimated_blocks_remaining()
+	}
+
+	pub fn is_major_syncing(&self) -> bool {
+		self.0.lock().expect("Lock is poisoned").is_major_syncing()
+	}
+
+	pub fn estimated_ticks_remaining(&self) -> Option<usize> {
+		self.0.lock().expect("Lock is poisoned").estimated_ticks_remaining()
+	}
+
+	pub fn progress(&self) -> SyncProgress {
+		self.0.lock().expect("Lock is poisoned").progress()
+	}
+
+	pub fn is_syncing(&self) -> bool {
+		self.0.lock().expect("Lock is poisoned").is_syncing()
+	}
+}
+
+impl<P, H, I> SharedNetworkSync<P, H, I> where
+	P: PartialEq + Eq + Hash + Clone,
+	H: PartialOrd,
+	I: BlockImporter,
+	I::Block: Clone,
+	I::Error: core::fmt::Debug,
+{
+	pub fn tick_now(&self) -> bool {
+		self.0.lock().expect("Lock is poisoned").tick_now()
+	}
+
+	pub fn import_now(&self) -> bool {
+		self.0.lock().expect("Lock is poisoned").import_now()
+	}
+
+	pub fn pop_event(&self) -> Option<SyncEvent<P, <I::Block as Block>::Identifier>> {
+		self.0.lock().expect("Lock is poisoned").pop_event()
+	}
+}
+
+impl<P, H, I> Stream for SharedNetworkSync<P, H, I> where
+	P: PartialEq + Eq + Hash + Clone + Unpin,
+	H: PartialOrd + Unpin,
+	I: BlockImporter + Unpin,
+	I::Block: Clone + Unpin,
+	<I::Block as Block>::Identifier: Unpin,
+	I::Error: core::fmt::Debug,
+{
+	type Item = SyncEvent<P, <I::Block as Block>::Identifier>;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		let mut sync = self.0.lock().expect("Lock is poisoned");
+		Pin::new(&mut *sync).poll_next(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::error as stderror;
+	use futures::task::noop_waker_ref;
+	use blockchain::SimpleBlock;
+	use blockchain::backend::{
+		SharedMemoryBackend, KeyValueMemoryState, ChainQuery, MemoryError, TreeRoute,
+	};
+	use blockchain::import::RecordingImporter;
+
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	struct TestBlock {
+		id: u64,
+		parent: u64,
+	}
+
+	impl Block for TestBlock {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.id }
+		fn parent_id(&self) -> Option<u64> { Some(self.parent) }
+	}
+
+	fn block(id: u64, parent: u64) -> TestBlock {
+		TestBlock { id, parent }
+	}
+
+	#[derive(Debug)]
+	struct TestError;
+
+	impl fmt::Display for TestError {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "{:?}", self)
+		}
+	}
+
+	impl stderror::Error for TestError { }
+
+	/// Importer on top of genesis block 0, accepting blocks whose parent
+	/// is already imported.
+	#[derive(Clone, Default)]
+	struct TestImporter {
+		imported: Arc<Mutex<HashSet<u64>>>,
+	}
+
+	impl TestImporter {
+		fn contains(&self, id: u64) -> bool {
+			id == 0 || self.imported.lock().expect("Lock is poisoned").contains(&id)
+		}
+
+		fn count(&self) -> usize {
+			self.imported.lock().expect("Lock is poisoned").len()
+		}
+	}
+
+	impl BlockImporter for TestImporter {
+		type Block = TestBlock;
+		type Error = TestError;
+
+		fn import_block(&mut self, block: TestBlock) -> Result<(), TestError> {
+			if !self.contains(block.parent) {
+				return Err(TestError)
+			}
+
+			self.imported.lock().expect("Lock is poisoned").insert(block.id);
+			Ok(())
+		}
+	}
+
+	type TestSync = NetworkSync<u32, usize, TestImporter>;
+
+	fn test_sync(config: SyncConfig) -> (TestSync, TestImporter) {
+		let importer = TestImporter::default();
+		(NetworkSync::new_without_timer(0, importer.clone(), config), importer)
+	}
+
+	fn poll(sync: &mut TestSync) -> Poll<Option<SyncEvent<u32, u64>>> {
+		let mut cx = Context::from_waker(noop_waker_ref());
+		Pin::new(sync).poll_next(&mut cx)
+	}
+
+	#[test]
+	fn imports_are_bounded_per_poll() {
+		let (mut sync, importer) = test_sync(SyncConfig {
+			max_pending_blocks: 2000,
+			max_imports_per_poll: 64,
+			..SyncConfig::fast_local()
+		});
+
+		// Block 1 comes last, so that everything else is retried in a
+		// second pass, while block 5000 never imports.
+		let mut blocks = (2..=1000).map(|id| block(id, id - 1)).collect::<Vec<_>>();
+		blocks.push(block(1, 0));
+		blocks.push(block(5000, 4999));
+		sync.note_blocks(blocks, None);
+
+		let mut polls = 0;
+		let mut imported = 0;
+		while !importer.contains(1000) {
+			let _ = poll(&mut sync);
+			polls += 1;
+			assert!(importer.count() - imported <= 64);
+			imported = importer.count();
+			assert!(polls < 2000);
+		}
+		assert!(polls >= 1000 / 64);
+
+		while sync.import_now() { }
+		assert!(sync.pending_blocks.is_empty());
+		assert!(!importer.contains(5000));
+	}
+
+	#[test]
+	fn tick_now_reports_remaining_blocks() {
+		let (mut sync, importer) = test_sync(SyncConfig {
+			max_imports_per_poll: 10,
+			..SyncConfig::fast_local()
+		});
+		sync.note_blocks((1..=25).map(|id| block(id, id - 1)).collect(), None);
+
+		assert!(sync.tick_now());
+		assert_eq!(importer.count(), 10);
+		assert!(sync.import_now());
+		assert!(!sync.tick_now());
+		assert_eq!(importer.count(), 25);
+	}
+
+	fn tick_until_timed_out(sync: &mut TestSync, peer: u32, max_ticks: usize) -> usize {
+		for ticks in 1..=max_ticks {
+			sync.tick_now();
+			while let Some(event) = sync.pop_event() {
+				if event == SyncEvent::PeerTimedOut(peer) {
+					return ticks
 				}
+			}
+		}
+		panic!("Peer did not time out")
+	}
+
+	#[test]
+	fn silent_peer_is_evicted() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		sync.note_connected(1);
+
+		assert_eq!(tick_until_timed_out(&mut sync, 1, 100), 20);
+		assert_eq!(sync.peer_count(), 0);
+	}
+
+	#[test]
+	fn rejoined_peer_is_not_evicted_early() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		sync.note_connected(1);
+		sync.note_peer_status(1, 5);
+		for _ in 0..5 {
+			sync.tick_now();
+		}
+		sync.note_disconnected(1);
+		for _ in 0..3 {
+			sync.tick_now();
+		}
+		sync.note_connected(1);
+
+		assert_eq!(tick_until_timed_out(&mut sync, 1, 100), 20);
+	}
+
+	#[test]
+	fn remaining_blocks_decrease_with_status() {
+		let (sync, _) = test_sync(SyncConfig::fast_local());
+		let mut sync = sync.with_status_height(|status: &usize| *status);
+		sync.note_connected(1);
+		sync.note_peer_height(1, 100);
+		assert_eq!(sync.estimated_blocks_remaining(), Some(100));
+		assert!(sync.is_major_syncing());
+
+		let mut remaining = 100;
+		for height in (10..=100).step_by(10) {
+			sync.tick_now();
+			sync.note_status(height);
+			let progress = sync.progress();
+			assert!(progress.blocks_remaining.unwrap() < remaining);
+			remaining = progress.blocks_remaining.unwrap();
+			assert_eq!(progress.height, Some(height));
+			assert_eq!(progress.best_peer_height, Some(100));
+		}
+		assert_eq!(remaining, 0);
+		assert_eq!(sync.estimated_ticks_remaining(), Some(0));
+		assert!(!sync.progress().is_major_syncing);
+		assert!(sync.debug_dump().contains("height: Some(100)"));
+	}
+
+	#[test]
+	fn agreeing_peers_are_not_synced_from() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		sync.note_connected(1);
+		sync.note_peer_status(1, 5);
+		assert!(sync.is_syncing());
+
+		sync.note_checksum([1; 32]);
+		sync.note_peer_checksum(1, [2; 32]);
+		assert!(!sync.is_in_agreement(&1));
+		assert!(sync.is_syncing());
+
+		sync.note_peer_checksum(1, [1; 32]);
+		assert!(sync.is_in_agreement(&1));
+		assert!(!sync.is_syncing());
 
-				self.last_sync = Some(self.tick);
+		sync.note_peer_status(1, 6);
+		assert!(!sync.is_in_agreement(&1));
+		assert!(sync.is_syncing());
+	}
+
+	#[test]
+	fn shared_sync_is_send_and_sync() {
+		fn assert_send_sync<T: Send + Sync>() { }
+		assert_send_sync::<SharedNetworkSync<u32, usize, TestImporter>>();
+	}
+
+	#[test]
+	fn recorded_imports_follow_chain_order() {
+		let recording = RecordingImporter::new(TestImporter::default());
+		let mut sync = NetworkSync::<u32, usize, _>::new_without_timer(
+			0,
+			recording.clone(),
+			SyncConfig::fast_local(),
+		);
+
+		sync.note_blocks((1..=10).rev().map(|id| block(id, id - 1)).collect(), None);
+		while sync.import_now() { }
+		assert_eq!(recording.recorded(), (1..=10).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn incompatible_peers_are_not_queried() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		for (peer, protocol_version) in &[(1, 1), (2, 0)] {
+			sync.note_connected_with_metadata(*peer, PeerMetadata {
+				protocol_version: Some(*protocol_version),
+				..Default::default()
+			});
+			sync.note_peer_status(*peer, 5);
+		}
+
+		let mut events = Vec::new();
+		for _ in 0..4 {
+			sync.tick_now();
+			while let Some(event) = sync.pop_event() {
+				events.push(event);
 			}
 		}
+		assert!(events.contains(&SyncEvent::QueryBlocks(2)));
+		assert!(events.iter().all(|event| match event {
+			SyncEvent::QueryPeerStatus(peer) | SyncEvent::QueryBlocks(peer) => *peer == 2,
+			_ => true,
+		}));
 
-		for event in new_events {
-			self.push_event(event);
+		let compatible = sync.peers_info().into_iter()
+			.map(|info| (info.peer, info.is_compatible))
+			.collect::<HashMap<_, _>>();
+		assert_eq!(compatible.get(&1), Some(&false));
+		assert_eq!(compatible.get(&2), Some(&true));
+	}
+
+	#[test]
+	fn requested_block_is_fetched_and_imported() {
+		let (mut sync, importer) = test_sync(SyncConfig::fast_local());
+		assert!(!sync.request_block(1));
+
+		sync.note_connected(1);
+		assert!(sync.request_block(1));
+		assert_eq!(sync.pop_event(), Some(SyncEvent::QueryBlock(1, 1)));
+
+		sync.note_blocks(vec![block(1, 0)], Some(1));
+		while sync.import_now() { }
+		assert!(importer.contains(1));
+	}
+
+	#[test]
+	fn duplicate_events_are_queued_once() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		for _ in 0..100 {
+			sync.push_event(SyncEvent::QueryStatus);
+			sync.push_event(SyncEvent::QueryBlocks(1));
 		}
 
-		if let Some(event) = self.pending_events.pop_front() {
-			Poll::Ready(Some(event))
-		} else {
-			Poll::Pending
+		assert_eq!(sync.pop_event(), Some(SyncEvent::QueryStatus));
+		assert_eq!(sync.pop_event(), Some(SyncEvent::QueryBlocks(1)));
+		assert_eq!(sync.pop_event(), None);
+
+		sync.push_event(SyncEvent::QueryStatus);
+		assert_eq!(sync.pop_event(), Some(SyncEvent::QueryStatus));
+	}
+
+	#[test]
+	fn same_depth_forks_request_blocks() {
+		let ours = CompositeStatus { depth: 5, head: 3u64 };
+		let theirs = CompositeStatus { depth: 5, head: 7u64 };
+		assert_ne!(ours, theirs);
+		assert!(theirs > ours);
+
+		let mut sync = NetworkSync::<u32, _, _>::new_without_timer(
+			ours,
+			TestImporter::default(),
+			SyncConfig::fast_local(),
+		);
+		sync.note_connected(1);
+		sync.note_peer_status(1, theirs);
+		assert!(sync.is_syncing());
+
+		let mut requested = false;
+		for _ in 0..4 {
+			sync.tick_now();
+			while let Some(event) = sync.pop_event() {
+				requested |= event == SyncEvent::QueryBlocks(1);
+			}
+		}
+		assert!(requested);
+	}
+
+	#[test]
+	fn blocks_past_queue_limit_are_rejected() {
+		let (mut sync, importer) = test_sync(SyncConfig {
+			max_pending_blocks: 10,
+			..SyncConfig::fast_local()
+		});
+
+		sync.note_blocks((1..=8).map(|id| block(id, id - 1)).collect(), None);
+		sync.note_blocks((9..=15).map(|id| block(id, id - 1)).collect(), None);
+		assert_eq!(sync.pending_blocks.len(), 10);
+		assert_eq!(sync.dropped_blocks(), 5);
+		assert_eq!(sync.pop_event(), Some(SyncEvent::PendingBlocksFull));
+
+		while sync.import_now() { }
+		assert!(importer.contains(10));
+		assert!(!importer.contains(11));
+	}
+
+	type TestBackend = SharedMemoryBackend<SimpleBlock<u64>, (), KeyValueMemoryState>;
+
+	/// Importer into a memory backend, following the longest chain.
+	struct LongestChainImporter(TestBackend);
+
+	impl BlockImporter for LongestChainImporter {
+		type Block = SimpleBlock<u64>;
+		type Error = MemoryError;
+
+		fn import_block(&mut self, block: SimpleBlock<u64>) -> Result<(), MemoryError> {
+			self.import_block_with_route(block)?;
+			Ok(())
+		}
+
+		fn import_block_with_route(
+			&mut self,
+			block: SimpleBlock<u64>,
+		) -> Result<Option<TreeRoute<SimpleBlock<u64>>>, MemoryError> {
+			let parent_id = block.parent_id().ok_or(MemoryError::IsGenesis)?;
+			let is_deeper = self.0.depth_at(&parent_id)? + 1 > self.0.depth_at(&self.0.head())?;
+
+			let mut action = self.0.begin_action();
+			let id = block.id();
+			action.import_block(block, Default::default());
+			if is_deeper {
+				action.set_head(id);
+			}
+			action.commit_with_route()
+		}
+	}
+
+	#[test]
+	fn reorg_event_carries_route() {
+		let backend = TestBackend::new_with_genesis(SimpleBlock::genesis(0, Vec::new()), Default::default());
+		let mut sync = NetworkSync::<u32, usize, _>::new_without_timer(
+			0,
+			LongestChainImporter(backend.clone()),
+			SyncConfig::fast_local(),
+		);
+		let chain = |blocks: &[(u64, u64)]| blocks.iter()
+			.map(|(id, parent)| SimpleBlock::new(*id, *parent, Vec::new()))
+			.collect::<Vec<_>>();
+
+		sync.note_blocks(chain(&[(1, 0), (2, 1)]), None);
+		while sync.import_now() { }
+		sync.note_blocks(chain(&[(10, 0), (11, 10), (12, 11)]), None);
+		while sync.import_now() { }
+		assert_eq!(backend.head(), 12);
+
+		let mut reorgs = Vec::new();
+		while let Some(event) = sync.pop_event() {
+			if let SyncEvent::Reorg { .. } = event {
+				reorgs.push(event);
+			}
+		}
+		assert_eq!(reorgs, vec![SyncEvent::Reorg { retracted: vec![2, 1], enacted: vec![10, 11, 12] }]);
+	}
+
+	#[test]
+	fn peer_count_follows_connections() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		sync.note_connected(1);
+		sync.note_connected(2);
+		assert_eq!(sync.peer_count(), 2);
+
+		sync.note_disconnected(1);
+		assert_eq!(sync.peer_count(), 1);
+		assert_eq!(sync.connected_peers().collect::<Vec<_>>(), vec![&2]);
+	}
+
+	#[test]
+	fn sync_waits_for_min_peers() {
+		let (mut sync, _) = test_sync(SyncConfig {
+			min_peers_to_sync: 2,
+			..SyncConfig::fast_local()
+		});
+		sync.note_connected(1);
+		sync.note_connected(2);
+
+		sync.note_peer_status(1, 5);
+		assert!(!sync.is_syncing());
+		sync.note_peer_status(2, 5);
+		assert!(sync.is_syncing());
+	}
+
+	#[test]
+	fn presets_are_valid_and_ordered() {
+		let presets = [SyncConfig::fast_local(), SyncConfig::wan_default(), SyncConfig::low_bandwidth()];
+		assert!(presets.iter().all(SyncConfig::is_valid));
+
+		for pair in presets.windows(2) {
+			assert!(pair[0].update_frequency < pair[1].update_frequency);
+			assert!(pair[0].peer_update_frequency < pair[1].peer_update_frequency);
+			assert!(pair[0].request_timeout < pair[1].request_timeout);
+		}
+		assert!(SyncConfig::low_bandwidth().max_pending_blocks < SyncConfig::wan_default().max_pending_blocks);
+	}
+
+	#[test]
+	fn only_large_gaps_are_major_syncs() {
+		let (sync, _) = test_sync(SyncConfig::fast_local());
+		let mut sync = sync.with_status_height(|status: &usize| *status);
+		sync.note_status(0);
+		sync.note_connected(1);
+
+		sync.note_peer_height(1, 1);
+		assert!(!sync.is_major_syncing());
+		sync.note_peer_height(1, 10000);
+		assert!(sync.is_major_syncing());
+	}
+
+	/// Strategy always requesting blocks from the lowest-id peer.
+	struct LowestPeerStrategy;
+
+	impl RequestStrategy<u32, usize, u64> for LowestPeerStrategy {
+		fn next_requests(&mut self, state: &SyncStateView<u32, usize>) -> Vec<SyncEvent<u32, u64>> {
+			if !state.is_syncing {
+				return Vec::new()
+			}
+
+			state.peers.iter()
+				.map(|peer| *peer.peer)
+				.min()
+				.map(|peer| vec![SyncEvent::QueryBlocks(peer)])
+				.unwrap_or_default()
+		}
+	}
+
+	#[test]
+	fn custom_strategy_is_honored() {
+		let (sync, _) = test_sync(SyncConfig::fast_local());
+		let mut sync = sync.with_request_strategy(LowestPeerStrategy);
+		for peer in &[9, 3, 5] {
+			sync.note_connected(*peer);
+			sync.note_peer_status(*peer, 10);
+		}
+
+		let mut queried = Vec::new();
+		for _ in 0..5 {
+			sync.tick_now();
+			while let Some(event) = sync.pop_event() {
+				if let SyncEvent::QueryBlocks(peer) = event {
+					queried.push(peer);
+				}
+			}
+		}
+		assert!(!queried.is_empty());
+		assert!(queried.iter().all(|peer| *peer == 3));
+	}
+
+	#[test]
+	fn blacklisted_blocks_are_never_imported() {
+		let (mut sync, importer) = test_sync(SyncConfig::fast_local());
+		sync.blacklist_block(1);
+
+		for _ in 0..3 {
+			sync.note_blocks(vec![block(1, 0), block(2, 1), block(3, 0)], None);
+			sync.tick_now();
+		}
+		assert!(!importer.contains(1));
+		assert!(!importer.contains(2));
+		assert!(importer.contains(3));
+		assert!(sync.is_blacklisted(&2));
+		assert!(sync.pending_blocks.is_empty());
+	}
+
+	#[test]
+	fn dump_lists_sync_state() {
+		let (mut sync, _) = test_sync(SyncConfig::fast_local());
+		sync.note_connected(7);
+		sync.note_peer_status(7, 12);
+		sync.note_peer_height(7, 12);
+		sync.note_height(3);
+		sync.tick_now();
+		sync.tick_now();
+		sync.note_blocks(vec![block(5, 4)], None);
+
+		let dump = sync.debug_dump();
+		for field in &[
+			"height: Some(3)", "tick: 2", "last_sync: Some(", "pending_blocks: 1", "peers: 1",
+			"7: height: Some(12)", "request_age: Some(",
+		] {
+			assert!(dump.contains(field), "Missing {:?} in dump:\n{}", field, dump);
 		}
 	}
 }
@@ -0,0 +1,59 @@
+use rand::seq::IteratorRandom;
+use super::{SyncConfig, SyncEvent};
+
+/// View of a connected peer exposed to request strategies.
+pub struct PeerView<'a, P, H> {
+	pub peer: &'a P,
+	pub head_status: Option<&'a H>,
+	pub is_compatible: bool,
+}
+
+/// View of the sync state exposed to request strategies.
+pub struct SyncStateView<'a, P, H> {
+	pub head_status: &'a H,
+	pub tick: usize,
+	pub last_sync: Option<usize>,
+	pub is_syncing: bool,
+	pub config: &'a SyncConfig,
+	pub peers: Vec<PeerView<'a, P, H>>,
+}
+
+/// Strategy deciding when, and from whom, to request blocks.
+///
+/// Returned `QueryBlocks` and `QueryBlock` requests mark their peer as
+/// having a pending request, and any returned request counts as a sync
+/// round for `SyncStateView::last_sync`.
+pub trait RequestStrategy<P, H, Id> {
+	fn next_requests(&mut self, state: &SyncStateView<P, H>) -> Vec<SyncEvent<P, Id>>;
+}
+
+/// Default request strategy. Once per `update_frequency` ticks while
+/// syncing, requests blocks from a random compatible peer if any
+/// compatible peer is ahead of us.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomPeerStrategy;
+
+impl<P: Clone, H: PartialOrd, Id> RequestStrategy<P, H, Id> for RandomPeerStrategy {
+	fn next_requests(&mut self, state: &SyncStateView<P, H>) -> Vec<SyncEvent<P, Id>> {
+		if !state.is_syncing {
+			return Vec::new()
+		}
+
+		let is_ahead = state.peers.iter()
+			.filter(|peer| peer.is_compatible)
+			.any(|peer| peer.head_status.map(|h| h > state.head_status).unwrap_or(false));
+		let is_due = state.last_sync
+			.map(|l| state.tick - l >= state.config.update_frequency)
+			.unwrap_or(true);
+
+		if !(is_ahead && is_due) {
+			return Vec::new()
+		}
+
+		state.peers.iter()
+			.filter(|peer| peer.is_compatible)
+			.choose(&mut rand::thread_rng())
+			.map(|peer| vec![SyncEvent::QueryBlocks(peer.peer.clone())])
+			.unwrap_or_default()
+	}
+}
@@ -1,8 +1,9 @@
 use std::{fmt, error as stderror};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use crate::{Block, Auxiliary};
-use crate::backend::{Store, BlockData, ChainQuery, ChainSettlement, Operation, Committable, SharedCommittable, OperationError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::{Block, Auxiliary, BlockExecutor, AsExternalities};
+use crate::import::{ImportAction, Error as ImportError, ForkChoice, BestDepth, best_leaf};
+use crate::backend::{lock_or_recover, ImportLock, TreeRoute, Store, ReadOnlyBackend, BlockData, ChainQuery, ChainSettlement, Operation, ImportOperation, Committable, SharedCommittable, OperationError};
 
 #[derive(Debug)]
 /// Memory errors
@@ -13,6 +14,8 @@ pub enum Error {
 	IsGenesis,
 	/// Query does not exist
 	NotExist,
+	/// Tree route exceeded the maximum allowed steps
+	RouteTooLong,
 }
 
 impl OperationError for Error {
@@ -23,6 +26,10 @@ impl OperationError for Error {
 	fn block_is_genesis() -> Self {
 		Error::IsGenesis
 	}
+
+	fn route_too_long() -> Self {
+		Error::RouteTooLong
+	}
 }
 
 impl fmt::Display for Error {
@@ -34,12 +41,29 @@ impl fmt::Display for Error {
 impl stderror::Error for Error { }
 
 /// Database backed by memory.
+#[derive(Clone)]
 pub struct MemoryDatabase<B: Block, A: Auxiliary<B>, S> {
 	blocks_and_states: HashMap<B::Identifier, BlockData<B, S>>,
 	head: B::Identifier,
 	genesis: B::Identifier,
 	canon_depth_mappings: HashMap<usize, B::Identifier>,
 	auxiliaries: HashMap<A::Key, A>,
+	fork_points: HashSet<B::Identifier>,
+}
+
+impl<B: Block, A: Auxiliary<B>, S> fmt::Debug for MemoryDatabase<B, A, S> where
+	B::Identifier: fmt::Debug,
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("MemoryDatabase")
+			.field("genesis", &self.genesis)
+			.field("head", &self.head)
+			.field("blocks", &self.blocks_and_states.len())
+			.field("canon_depth", &self.canon_depth_mappings.len().saturating_sub(1))
+			.field("auxiliaries", &self.auxiliaries.len())
+			.field("fork_points", &self.fork_points.len())
+			.finish()
+	}
 }
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> Store for MemoryDatabase<B, A, S> {
@@ -84,6 +108,10 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for MemoryDatabase<B, A, S>
 		Ok(self.auxiliaries.get(key).map(|v| v.clone()))
 	}
 
+	fn auxiliaries(&self) -> Result<Vec<(A::Key, A)>, Error> {
+		Ok(self.auxiliaries.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+	}
+
 	fn children_at(
 		&self,
 		id: &B::Identifier,
@@ -93,6 +121,17 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for MemoryDatabase<B, A, S>
 			.ok_or(Error::NotExist)
 	}
 
+	fn children_paginated(
+		&self,
+		id: &B::Identifier,
+		offset: usize,
+		limit: usize,
+	) -> Result<Vec<B::Identifier>, Error> {
+		self.blocks_and_states.get(id)
+			.map(|data| data.children.iter().skip(offset).take(limit).cloned().collect())
+			.ok_or(Error::NotExist)
+	}
+
 	fn depth_at(
 		&self,
 		id: &B::Identifier
@@ -119,6 +158,43 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for MemoryDatabase<B, A, S>
 			.map(|data| data.state.clone())
 			.ok_or(Error::NotExist)
 	}
+
+	fn with_state_at<R, F: FnOnce(&S) -> R>(
+		&self,
+		id: &B::Identifier,
+		f: F,
+	) -> Result<R, Error> {
+		self.blocks_and_states.get(id)
+			.map(|data| f(&data.state))
+			.ok_or(Error::NotExist)
+	}
+
+	fn state_root_at(
+		&self,
+		id: &B::Identifier,
+	) -> Result<Option<Vec<u8>>, Error> {
+		self.blocks_and_states.get(id)
+			.map(|data| data.state_root.clone())
+			.ok_or(Error::NotExist)
+	}
+
+	fn blocks_at_depth(&self, depth: usize) -> Result<Vec<B::Identifier>, Error> {
+		Ok(self.blocks_and_states.iter()
+		   .filter(|(_, data)| data.depth == depth)
+		   .map(|(id, _)| id.clone())
+		   .collect())
+	}
+
+	fn fork_points(&self) -> Result<Vec<B::Identifier>, Error> {
+		Ok(self.fork_points.iter().cloned().collect())
+	}
+
+	fn leaves(&self) -> Result<Vec<B::Identifier>, Error> {
+		Ok(self.blocks_and_states.iter()
+		   .filter(|(_, data)| data.children.is_empty())
+		   .map(|(id, _)| id.clone())
+		   .collect())
+	}
 }
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> ChainSettlement for MemoryDatabase<B, A, S> {
@@ -132,17 +208,32 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainSettlement for MemoryDatabase<B,
 		is_canon: bool
 	) {
 		self.blocks_and_states.insert(id, BlockData {
-			block, state, depth, children, is_canon
+			block, state, depth, children, is_canon,
+			state_root: None,
 		});
 	}
 	fn push_child(
 		&mut self,
 		id: <Self::Block as Block>::Identifier,
 		child: <Self::Block as Block>::Identifier,
+	) {
+		let children = &mut self.blocks_and_states.get_mut(&id)
+			.expect("Internal database error")
+			.children;
+		children.push(child);
+
+		if children.len() == 2 {
+			self.fork_points.insert(id);
+		}
+	}
+	fn set_state_root(
+		&mut self,
+		id: <Self::Block as Block>::Identifier,
+		state_root: Vec<u8>,
 	) {
 		self.blocks_and_states.get_mut(&id)
 			.expect("Internal database error")
-			.children.push(child);
+			.state_root = Some(state_root);
 	}
 	fn set_canon(
 		&mut self,
@@ -188,15 +279,30 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainSettlement for MemoryDatabase<B,
 }
 
 /// Memory backend
+#[derive(Clone)]
 pub struct MemoryBackend<B: Block, A: Auxiliary<B>, S>(MemoryDatabase<B, A, S>);
 
+impl<B: Block, A: Auxiliary<B>, S> fmt::Debug for MemoryBackend<B, A, S> where
+	B::Identifier: fmt::Debug,
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_tuple("MemoryBackend").field(&self.0).finish()
+	}
+}
+
 impl<B: Block, A: Auxiliary<B>, S: Clone> MemoryBackend<B, A, S> {
 	/// Create a new memory backend from genesis.
 	pub fn new_with_genesis(block: B, genesis_state: S) -> Self {
+		Self::with_capacity(block, genesis_state, 0)
+	}
+
+	/// Create a new memory backend from genesis, pre-allocating space for
+	/// the given number of blocks.
+	pub fn with_capacity(block: B, genesis_state: S, capacity: usize) -> Self {
 		assert!(block.parent_id().is_none(), "with_genesis must be provided with a genesis block");
 
 		let genesis_id = block.id();
-		let mut blocks_and_states = HashMap::new();
+		let mut blocks_and_states = HashMap::with_capacity(capacity);
 		blocks_and_states.insert(
 			block.id(),
 			BlockData {
@@ -205,15 +311,17 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> MemoryBackend<B, A, S> {
 				depth: 0,
 				children: Vec::new(),
 				is_canon: true,
+				state_root: None,
 			}
 		);
-		let mut canon_depth_mappings = HashMap::new();
+		let mut canon_depth_mappings = HashMap::with_capacity(capacity);
 		canon_depth_mappings.insert(0, genesis_id.clone());
 
 		Self(MemoryDatabase {
 			blocks_and_states,
 			canon_depth_mappings,
 			auxiliaries: Default::default(),
+			fork_points: Default::default(),
 			genesis: genesis_id.clone(),
 			head: genesis_id,
 		})
@@ -258,6 +366,11 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for MemoryBackend<B, A, S>
 	) -> Result<Option<Self::Auxiliary>, Self::Error> {
 		Ok(self.0.auxiliary(key)?)
 	}
+	fn auxiliaries(
+		&self,
+	) -> Result<Vec<(<Self::Auxiliary as Auxiliary<Self::Block>>::Key, Self::Auxiliary)>, Self::Error> {
+		Ok(self.0.auxiliaries()?)
+	}
 	fn depth_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
@@ -270,18 +383,55 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for MemoryBackend<B, A, S>
 	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
 		Ok(self.0.children_at(hash)?)
 	}
+	fn children_paginated(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		offset: usize,
+		limit: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.children_paginated(hash, offset, limit)?)
+	}
 	fn state_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Self::State, Self::Error> {
 		Ok(self.0.state_at(hash)?)
 	}
+	fn with_state_at<R, F: FnOnce(&Self::State) -> R>(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		f: F,
+	) -> Result<R, Self::Error> {
+		Ok(self.0.with_state_at(hash, f)?)
+	}
 	fn block_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Self::Block, Self::Error> {
 		Ok(self.0.block_at(hash)?)
 	}
+	fn state_root_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		Ok(self.0.state_root_at(hash)?)
+	}
+	fn blocks_at_depth(
+		&self,
+		depth: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.blocks_at_depth(depth)?)
+	}
+	fn fork_points(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.fork_points()?)
+	}
+	fn leaves(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.0.leaves()?)
+	}
 }
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> Committable for MemoryBackend<B, A, S> {
@@ -293,17 +443,241 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> Committable for MemoryBackend<B, A, S>
 	) -> Result<(), Self::Error> {
 		operation.settle(&mut self.0)
 	}
+
+	fn commit_with_route(
+		&mut self,
+		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		operation.settle_with_route(&mut self.0)
+	}
 }
 
-/// Shared memory backend
-pub struct SharedMemoryBackend<B: Block, A: Auxiliary<B>, S>(
-	Arc<RwLock<MemoryBackend<B, A, S>>>
-);
+/// Commit batching configuration for shared memory backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchConfig {
+	/// Whether to settle every commit immediately. When disabled, commits
+	/// are buffered until `batch_size` is reached or `flush` is called.
+	/// Buffered operations are not validated when committed, and are not
+	/// visible to `ChainQuery` until flushed, as reads do not flush. The
+	/// buffer is settled as one merged operation, so an invalid buffered
+	/// operation rejects the whole buffer, and is reported to whichever
+	/// call triggers the flush.
+	pub auto_flush: bool,
+	/// Number of buffered operations that triggers a flush.
+	pub batch_size: usize,
+}
+
+impl Default for BatchConfig {
+	fn default() -> Self {
+		Self {
+			auto_flush: true,
+			batch_size: 1,
+		}
+	}
+}
+
+struct CommitBatch<B: Block, A: Auxiliary<B>, S> {
+	config: BatchConfig,
+	pending: VecDeque<Operation<B, S, A>>,
+}
+
+/// Shared memory backend. With batching enabled, reads only see settled
+/// operations, not the ones still buffered.
+pub struct SharedMemoryBackend<B: Block, A: Auxiliary<B>, S> {
+	backend: Arc<RwLock<MemoryBackend<B, A, S>>>,
+	batch: Arc<Mutex<CommitBatch<B, A, S>>>,
+	import_lock: ImportLock,
+}
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> SharedMemoryBackend<B, A, S> {
 	/// Create a new memory backend from genesis.
 	pub fn new_with_genesis(block: B, genesis_state: S) -> Self {
-		Self(Arc::new(RwLock::new(MemoryBackend::new_with_genesis(block, genesis_state))))
+		Self::new_with_genesis_and_config(block, genesis_state, Default::default())
+	}
+
+	/// Create a new memory backend from genesis, with the given commit
+	/// batching configuration.
+	pub fn new_with_genesis_and_config(
+		block: B,
+		genesis_state: S,
+		config: BatchConfig,
+	) -> Self {
+		Self {
+			backend: Arc::new(RwLock::new(MemoryBackend::new_with_genesis(block, genesis_state))),
+			batch: Arc::new(Mutex::new(CommitBatch { config, pending: VecDeque::new() })),
+			import_lock: ImportLock::new(),
+		}
+	}
+
+	/// Set whether to recover from poisoned locks instead of panicking.
+	/// The setting is shared with all clones of the backend.
+	pub fn with_recover_poison(self, recover_poison: bool) -> Self {
+		Self {
+			import_lock: self.import_lock.with_recover_poison(recover_poison),
+			..self
+		}
+	}
+
+	/// Guard imports of the backend with the given lock, so that importers
+	/// holding the lock outside the backend are serialized with its own
+	/// import actions.
+	pub fn with_import_lock(self, import_lock: ImportLock) -> Self {
+		Self { import_lock, ..self }
+	}
+
+	/// Lock the import of the backend.
+	pub fn lock_import(&self) -> MutexGuard<'_, ()> {
+		self.import_lock.lock()
+	}
+
+	/// Begin an import action, holding the import lock until it is
+	/// committed or dropped.
+	pub fn begin_action(&self) -> ImportAction<'_, Self> {
+		ImportAction::new(self, self.lock_import())
+	}
+
+	/// Commit an import action.
+	pub fn commit_action(&self, action: ImportAction<'_, Self>) -> Result<(), Error> {
+		action.commit()
+	}
+
+	/// Execute a block against its parent state and import it, setting the
+	/// deepest leaf as head. Returns whether the head changed.
+	pub fn import_and_set_head<E>(
+		&self,
+		executor: &E,
+		block: B,
+	) -> Result<bool, ImportError> where
+		E: BlockExecutor<Block=B>,
+		S: AsExternalities<E::Externalities>,
+	{
+		self.import_with_fork_choice(executor, block, &BestDepth)
+	}
+
+	/// Execute a block against its parent state and import it, then set the
+	/// best of all leaves under the fork choice rule as head, which need not
+	/// be the imported block. Returns whether the head changed.
+	pub fn import_with_fork_choice<E, F>(
+		&self,
+		executor: &E,
+		block: B,
+		fork_choice: &F,
+	) -> Result<bool, ImportError> where
+		E: BlockExecutor<Block=B>,
+		S: AsExternalities<E::Externalities>,
+		F: ForkChoice<MemoryBackend<B, A, S>>,
+	{
+		let backend_error = |e: Error| ImportError::Backend(Box::new(e));
+
+		let _guard = self.lock_import();
+		self.flush().map_err(backend_error)?;
+		let parent_id = block.parent_id()
+			.ok_or_else(|| backend_error(Error::IsGenesis))?;
+		let mut state = self.state_at(&parent_id).map_err(backend_error)?;
+		executor.set_parent_context(state.as_externalities(), &parent_id);
+		executor.execute_block(&block, state.as_externalities())
+			.map_err(|e| ImportError::Executor(Box::new(e)))?;
+
+		self.settle_executed(block, state, fork_choice)
+	}
+
+	/// Import a block, together with its state from executing it against its
+	/// parent state, then set the best of all leaves under the fork choice
+	/// rule as head. Returns whether the head changed.
+	pub fn import_executed<F>(
+		&self,
+		block: B,
+		state: S,
+		fork_choice: &F,
+	) -> Result<bool, ImportError> where
+		F: ForkChoice<MemoryBackend<B, A, S>>,
+	{
+		let _guard = self.lock_import();
+		self.settle_executed(block, state, fork_choice)
+	}
+
+	fn settle_executed<F>(
+		&self,
+		block: B,
+		state: S,
+		fork_choice: &F,
+	) -> Result<bool, ImportError> where
+		F: ForkChoice<MemoryBackend<B, A, S>>,
+	{
+		let backend_error = |e: Error| ImportError::Backend(Box::new(e));
+
+		// Import and choose the head under one write lock, so that readers
+		// never see the block without the head it leads to.
+		let mut batch = self.batch();
+		self.flush_pending(&mut batch).map_err(backend_error)?;
+		let mut backend = self.write();
+		backend.commit(Operation {
+			import_block: vec![ImportOperation { block, state }],
+			..Default::default()
+		}).map_err(backend_error)?;
+
+		let best = best_leaf(&*backend, fork_choice).map_err(backend_error)?;
+		if best == backend.head() {
+			return Ok(false)
+		}
+		backend.commit(Operation {
+			set_head: Some(best),
+			..Default::default()
+		}).map_err(backend_error)?;
+
+		Ok(true)
+	}
+
+	/// Create an independent copy of the backend. Unlike `clone`, which
+	/// shares the underlying chain through an `Arc`, imports into the copy
+	/// do not affect the original, and vice versa. Only settled blocks are
+	/// copied; operations still buffered for batching are not.
+	pub fn clone_deep(&self) -> Self {
+		let config = self.batch().config;
+
+		Self {
+			backend: Arc::new(RwLock::new(self.read().clone())),
+			batch: Arc::new(Mutex::new(CommitBatch { config, pending: VecDeque::new() })),
+			import_lock: ImportLock::new().with_recover_poison(self.import_lock.recover_poison()),
+		}
+	}
+
+	/// Get a read-only proxy of the backend.
+	pub fn read_only(&self) -> ReadOnlyBackend<Self> {
+		ReadOnlyBackend::new(self.clone())
+	}
+
+	/// Settle all buffered operations, merged in order into a single
+	/// operation. If it fails, none of the buffered operations are settled,
+	/// and all of them are discarded.
+	pub fn flush(&self) -> Result<(), Error> {
+		let mut batch = self.batch();
+		self.flush_pending(&mut batch)
+	}
+
+	fn read(&self) -> RwLockReadGuard<'_, MemoryBackend<B, A, S>> {
+		lock_or_recover(self.backend.read(), self.import_lock.recover_poison())
+	}
+
+	fn write(&self) -> RwLockWriteGuard<'_, MemoryBackend<B, A, S>> {
+		lock_or_recover(self.backend.write(), self.import_lock.recover_poison())
+	}
+
+	fn batch(&self) -> MutexGuard<'_, CommitBatch<B, A, S>> {
+		lock_or_recover(self.batch.lock(), self.import_lock.recover_poison())
+	}
+
+	fn flush_pending(&self, batch: &mut CommitBatch<B, A, S>) -> Result<(), Error> {
+		let mut pending = batch.pending.drain(..);
+		let mut operation = match pending.next() {
+			Some(operation) => operation,
+			None => return Ok(()),
+		};
+		for other in pending {
+			operation.merge(other);
+		}
+
+		self.write().commit(operation)
 	}
 }
 
@@ -316,64 +690,110 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> Store for SharedMemoryBackend<B, A, S>
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> ChainQuery for SharedMemoryBackend<B, A, S> {
 	fn genesis(&self) -> <Self::Block as Block>::Identifier {
-		self.0.read().expect("Lock is poisoned").genesis()
+		self.read().genesis()
 	}
 	fn head(&self) -> <Self::Block as Block>::Identifier {
-		self.0.read().expect("Lock is poisoned").head()
+		self.read().head()
 	}
 	fn contains(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<bool, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").contains(hash)?)
+		Ok(self.read().contains(hash)?)
 	}
 	fn is_canon(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<bool, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").is_canon(hash)?)
+		Ok(self.read().is_canon(hash)?)
 	}
 	fn lookup_canon_depth(
 		&self,
 		depth: usize,
 	) -> Result<Option<<Self::Block as Block>::Identifier>, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").lookup_canon_depth(depth)?)
+		Ok(self.read().lookup_canon_depth(depth)?)
 	}
 	fn auxiliary(
 		&self,
 		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
 	) -> Result<Option<Self::Auxiliary>, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").auxiliary(key)?)
+		Ok(self.read().auxiliary(key)?)
+	}
+	fn auxiliaries(
+		&self,
+	) -> Result<Vec<(<Self::Auxiliary as Auxiliary<Self::Block>>::Key, Self::Auxiliary)>, Self::Error> {
+		Ok(self.read().auxiliaries()?)
 	}
 	fn depth_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<usize, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").depth_at(hash)?)
+		Ok(self.read().depth_at(hash)?)
 	}
 	fn children_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").children_at(hash)?)
+		Ok(self.read().children_at(hash)?)
+	}
+	fn children_paginated(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		offset: usize,
+		limit: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.read().children_paginated(hash, offset, limit)?)
 	}
 	fn state_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Self::State, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").state_at(hash)?)
+		Ok(self.read().state_at(hash)?)
+	}
+	fn with_state_at<R, F: FnOnce(&Self::State) -> R>(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		f: F,
+	) -> Result<R, Self::Error> {
+		Ok(self.read().with_state_at(hash, f)?)
 	}
 	fn block_at(
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Self::Block, Self::Error> {
-		Ok(self.0.read().expect("Lock is poisoned").block_at(hash)?)
+		Ok(self.read().block_at(hash)?)
+	}
+	fn state_root_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		Ok(self.read().state_root_at(hash)?)
+	}
+	fn blocks_at_depth(
+		&self,
+		depth: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.read().blocks_at_depth(depth)?)
+	}
+	fn fork_points(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.read().fork_points()?)
+	}
+	fn leaves(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.read().leaves()?)
 	}
 }
 
 impl<B: Block, A: Auxiliary<B>, S: Clone> Clone for SharedMemoryBackend<B, A, S> {
 	fn clone(&self) -> Self {
-		Self(self.0.clone())
+		Self {
+			backend: self.backend.clone(),
+			batch: self.batch.clone(),
+			import_lock: self.import_lock.clone(),
+		}
 	}
 }
 
@@ -384,6 +804,575 @@ impl<B: Block, A: Auxiliary<B>, S: Clone> SharedCommittable for SharedMemoryBack
 		&self,
 		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
 	) -> Result<(), Self::Error> {
-		self.0.write().expect("Lock is poisoned").commit(operation)
+		let mut batch = self.batch();
+
+		if batch.config.auto_flush {
+			self.flush_pending(&mut batch)?;
+			return self.write().commit(operation)
+		}
+
+		batch.pending.push_back(operation);
+		if batch.pending.len() >= batch.config.batch_size {
+			self.flush_pending(&mut batch)?;
+		}
+
+		Ok(())
+	}
+
+	fn commit_with_route(
+		&self,
+		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		let mut batch = self.batch();
+
+		self.flush_pending(&mut batch)?;
+		self.write().commit_with_route(operation)
+	}
+
+	fn commit_if_head(
+		&self,
+		expected_head: &<Self::Block as Block>::Identifier,
+		operation: Operation<Self::Block, Self::State, Self::Auxiliary>,
+	) -> Result<bool, Self::Error> {
+		let mut batch = self.batch();
+
+		self.flush_pending(&mut batch)?;
+		let mut backend = self.write();
+		if backend.head() != *expected_head {
+			return Ok(false)
+		}
+
+		backend.commit(operation)?;
+		Ok(true)
+	}
+
+	fn flush(&self) -> Result<(), Self::Error> {
+		SharedMemoryBackend::flush(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Barrier;
+	use core::convert::Infallible;
+	use crate::{FunctionalExecutor, FunctionalAdapter, StorageExternalities};
+	use crate::backend::KeyValueMemoryState;
+	use std::thread;
+	use std::time::Duration;
+	use crate::test_support::{
+		DummyBlock, Note, SharedTestBackend, CounterExecutor, CounterError, counter, generate_random_chain,
+		import_operation, import_chain, import_shared_chain, shared_test_backend, test_backend,
+	};
+
+	fn batched_backend(batch_size: usize) -> SharedTestBackend {
+		SharedMemoryBackend::new_with_genesis_and_config(
+			DummyBlock::genesis(),
+			Default::default(),
+			BatchConfig { auto_flush: false, batch_size },
+		)
+	}
+
+	#[test]
+	fn batched_commits_match_immediate_commits() {
+		let blocks = generate_random_chain(3, 100, 0.2);
+
+		let mut immediate = test_backend();
+		import_chain(&mut immediate, &blocks, true).unwrap();
+
+		let batched = batched_backend(16);
+		import_shared_chain(&batched, &blocks, true).unwrap();
+		batched.flush().unwrap();
+
+		assert_eq!(batched.head(), immediate.head());
+		let mut batched_leaves = batched.leaves().unwrap();
+		let mut immediate_leaves = immediate.leaves().unwrap();
+		batched_leaves.sort();
+		immediate_leaves.sort();
+		assert_eq!(batched_leaves, immediate_leaves);
+		for block in &blocks {
+			assert_eq!(batched.depth_at(&block.id).unwrap(), immediate.depth_at(&block.id).unwrap());
+			assert_eq!(batched.is_canon(&block.id).unwrap(), immediate.is_canon(&block.id).unwrap());
+		}
+	}
+
+	#[test]
+	fn commit_if_head_race_has_one_winner() {
+		let backend = shared_test_backend();
+		let barrier = Arc::new(Barrier::new(2));
+
+		let handles = (1..=2).map(|id| {
+			let backend = backend.clone();
+			let barrier = barrier.clone();
+
+			thread::spawn(move || {
+				let mut retries = 0;
+				loop {
+					let head = backend.head();
+					let mut operation = import_operation(&[DummyBlock::new(id, head)]);
+					operation.set_head = Some(id);
+
+					if retries == 0 {
+						barrier.wait();
+					}
+					if backend.commit_if_head(&head, operation).unwrap() {
+						return retries
+					}
+					retries += 1;
+				}
+			})
+		}).collect::<Vec<_>>();
+
+		let mut retries = handles.into_iter()
+			.map(|handle| handle.join().unwrap())
+			.collect::<Vec<_>>();
+		retries.sort();
+		assert_eq!(retries, vec![0, 1]);
+		assert_eq!(backend.depth_at(&backend.head()).unwrap(), 2);
+		assert_eq!(backend.leaves().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn auxiliaries_are_enumerated() {
+		let mut backend = MemoryBackend::<DummyBlock, Note, KeyValueMemoryState>::new_with_genesis(
+			DummyBlock::genesis(),
+			Default::default(),
+		);
+		assert!(backend.auxiliaries().unwrap().is_empty());
+
+		let notes = (0..5).map(|key| Note { key, value: key as u64 * 10 }).collect::<Vec<_>>();
+		backend.commit(Operation {
+			insert_auxiliaries: notes.clone(),
+			..Default::default()
+		}).unwrap();
+		backend.commit(Operation {
+			remove_auxiliaries: vec![3],
+			..Default::default()
+		}).unwrap();
+
+		let mut auxiliaries = backend.auxiliaries().unwrap();
+		auxiliaries.sort_by_key(|(key, _)| *key);
+		let expected = notes.into_iter()
+			.filter(|note| note.key != 3)
+			.map(|note| (note.key, note))
+			.collect::<Vec<_>>();
+		assert_eq!(auxiliaries, expected);
+	}
+
+	#[test]
+	fn import_and_set_head_follows_longest_chain() {
+		let executor = CounterExecutor::default();
+
+		for config in [BatchConfig::default(), BatchConfig { auto_flush: false, batch_size: 16 }].iter() {
+			let backend = SharedTestBackend::new_with_genesis_and_config(
+				DummyBlock::genesis(),
+				Default::default(),
+				*config,
+			);
+
+			for id in 1..=3 {
+				assert!(backend.import_and_set_head(&executor, DummyBlock::new(id, id - 1)).unwrap());
+			}
+			assert!(!backend.import_and_set_head(&executor, DummyBlock::new(10, 1)).unwrap());
+			assert!(!backend.import_and_set_head(&executor, DummyBlock::new(11, 10)).unwrap());
+			assert!(backend.import_and_set_head(&executor, DummyBlock::new(12, 11)).unwrap());
+
+			backend.flush().unwrap();
+			assert_eq!(backend.head(), 12);
+			assert!(!backend.is_canon(&3).unwrap());
+			assert_eq!(counter(&mut backend.state_at(&12).unwrap()), 4);
+			assert_eq!(counter(&mut backend.state_at(&3).unwrap()), 3);
+		}
+	}
+
+	#[test]
+	fn state_roots_are_read_back() {
+		let mut backend = test_backend();
+		let blocks = (1..=5).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		let mut operation = import_operation(&blocks);
+		operation.insert_state_roots = blocks.iter()
+			.map(|block| (block.id, vec![block.id as u8; 4]))
+			.collect();
+		backend.commit(operation).unwrap();
+
+		for block in &blocks {
+			assert_eq!(backend.state_root_at(&block.id).unwrap(), Some(vec![block.id as u8; 4]));
+		}
+		assert_eq!(backend.state_root_at(&0).unwrap(), None);
+		assert!(backend.state_root_at(&6).is_err());
+	}
+
+	#[test]
+	fn buffer_is_settled_as_one_operation() {
+		let batched = batched_backend(10);
+		batched.commit(import_operation(&[DummyBlock::new(1, 0)])).unwrap();
+		batched.commit(import_operation(&[DummyBlock::new(3, 2)])).unwrap();
+		batched.commit(import_operation(&[DummyBlock::new(2, 1)])).unwrap();
+		assert!(!batched.contains(&1).unwrap());
+
+		batched.flush().unwrap();
+		assert!(batched.contains(&3).unwrap());
+
+		batched.commit(import_operation(&[DummyBlock::new(4, 3)])).unwrap();
+		batched.commit(import_operation(&[DummyBlock::new(6, 5)])).unwrap();
+		assert!(batched.flush().is_err());
+		assert!(!batched.contains(&4).unwrap());
+
+		batched.flush().unwrap();
+		assert!(!batched.contains(&4).unwrap());
+	}
+
+	#[test]
+	fn fork_points_are_listed() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[
+			DummyBlock::new(1, 0),
+			DummyBlock::new(2, 0),
+			DummyBlock::new(3, 1),
+			DummyBlock::new(4, 1),
+			DummyBlock::new(5, 3),
+		], false).unwrap();
+
+		let mut fork_points = backend.fork_points().unwrap();
+		fork_points.sort();
+		assert_eq!(fork_points, vec![0, 1]);
+	}
+
+	#[test]
+	fn wide_children_are_paginated() {
+		let backend = shared_test_backend();
+		let blocks = (1..=1000).map(|id| DummyBlock::new(id, 0)).collect::<Vec<_>>();
+		backend.commit(import_operation(&blocks)).unwrap();
+
+		let mut children = Vec::new();
+		let mut offset = 0;
+		loop {
+			let page = backend.children_paginated(&0, offset, 64).unwrap();
+			assert!(page.len() <= 64);
+			if page.is_empty() {
+				break
+			}
+			offset += page.len();
+			children.extend(page);
+		}
+
+		children.sort();
+		assert_eq!(children, (1..=1000).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn action_is_committed_through_backend() {
+		let backend = shared_test_backend();
+		let mut action = backend.begin_action();
+		action.import_block(DummyBlock::new(1, 0), Default::default());
+		action.set_head(1);
+		backend.commit_action(action).unwrap();
+
+		assert_eq!(backend.head(), 1);
+		// The import lock is released on commit.
+		let _action = backend.begin_action();
+	}
+
+	type TestDatabase = MemoryDatabase<DummyBlock, (), KeyValueMemoryState>;
+
+	/// Database counting settlement calls.
+	struct CountingDatabase {
+		database: TestDatabase,
+		settlements: usize,
+		/// Block whose lookup returns its child instead, as if the
+		/// database were inconsistent.
+		mislabeled: Option<u64>,
+	}
+
+	impl Store for CountingDatabase {
+		type Block = DummyBlock;
+		type State = KeyValueMemoryState;
+		type Auxiliary = ();
+		type Error = Error;
+	}
+
+	impl ChainQuery for CountingDatabase {
+		fn genesis(&self) -> u64 { self.database.genesis() }
+		fn head(&self) -> u64 { self.database.head() }
+		fn contains(&self, id: &u64) -> Result<bool, Error> { self.database.contains(id) }
+		fn is_canon(&self, id: &u64) -> Result<bool, Error> { self.database.is_canon(id) }
+		fn lookup_canon_depth(&self, depth: usize) -> Result<Option<u64>, Error> {
+			self.database.lookup_canon_depth(depth)
+		}
+		fn auxiliary(&self, key: &()) -> Result<Option<()>, Error> { self.database.auxiliary(key) }
+		fn auxiliaries(&self) -> Result<Vec<((), ())>, Error> { self.database.auxiliaries() }
+		fn depth_at(&self, id: &u64) -> Result<usize, Error> { self.database.depth_at(id) }
+		fn children_at(&self, id: &u64) -> Result<Vec<u64>, Error> { self.database.children_at(id) }
+		fn state_at(&self, id: &u64) -> Result<KeyValueMemoryState, Error> { self.database.state_at(id) }
+		fn block_at(&self, id: &u64) -> Result<DummyBlock, Error> {
+			if self.mislabeled == Some(*id) {
+				return Ok(DummyBlock::new(id + 1, *id))
+			}
+			self.database.block_at(id)
+		}
+	}
+
+	impl ChainSettlement for CountingDatabase {
+		fn insert_block(
+			&mut self,
+			id: u64,
+			block: DummyBlock,
+			state: KeyValueMemoryState,
+			depth: usize,
+			children: Vec<u64>,
+			is_canon: bool,
+		) {
+			self.settlements += 1;
+			self.database.insert_block(id, block, state, depth, children, is_canon)
+		}
+		fn push_child(&mut self, id: u64, child: u64) {
+			self.settlements += 1;
+			self.database.push_child(id, child)
+		}
+		fn set_canon(&mut self, id: u64, is_canon: bool) {
+			self.settlements += 1;
+			self.database.set_canon(id, is_canon)
+		}
+		fn insert_canon_depth_mapping(&mut self, depth: usize, id: u64) {
+			self.settlements += 1;
+			self.database.insert_canon_depth_mapping(depth, id)
+		}
+		fn remove_canon_depth_mapping(&mut self, depth: &usize) {
+			self.settlements += 1;
+			self.database.remove_canon_depth_mapping(depth)
+		}
+		fn insert_auxiliary(&mut self, key: (), value: ()) {
+			self.settlements += 1;
+			self.database.insert_auxiliary(key, value)
+		}
+		fn remove_auxiliary(&mut self, key: &()) {
+			self.settlements += 1;
+			self.database.remove_auxiliary(key)
+		}
+		fn set_head(&mut self, head: u64) {
+			self.settlements += 1;
+			self.database.set_head(head)
+		}
+	}
+
+	#[test]
+	fn settling_same_operation_twice_is_no_op() {
+		let operation = || {
+			let mut operation = import_operation(&[DummyBlock::new(1, 0), DummyBlock::new(2, 1)]);
+			operation.set_head = Some(2);
+			operation
+		};
+		let mut database = CountingDatabase { database: test_backend().0, settlements: 0, mislabeled: None };
+
+		operation().settle(&mut database).unwrap();
+		assert!(database.settlements > 0);
+		assert_eq!(database.head(), 2);
+
+		database.settlements = 0;
+		operation().settle(&mut database).unwrap();
+		assert_eq!(database.settlements, 0);
+		assert_eq!(database.children_at(&1).unwrap(), vec![2]);
+	}
+
+	#[test]
+	fn route_not_ending_at_new_head_is_rejected_without_changes() {
+		let mut database = CountingDatabase { database: test_backend().0, settlements: 0, mislabeled: None };
+		import_operation(&[DummyBlock::new(1, 0), DummyBlock::new(2, 1)])
+			.settle(&mut database).unwrap();
+		Operation { set_head: Some(2), ..Default::default() }
+			.settle(&mut database).unwrap();
+
+		// Looking up block 1 yields block 2, so the route from 2 to 1 ends
+		// at 2 rather than at the requested head.
+		database.mislabeled = Some(1);
+		database.settlements = 0;
+		let mut operation = import_operation(&[DummyBlock::new(3, 2)]);
+		operation.set_head = Some(1);
+		assert!(operation.settle(&mut database).is_err());
+
+		assert_eq!(database.settlements, 0);
+		assert_eq!(database.head(), 2);
+		assert!(!database.contains(&3).unwrap());
+		assert_eq!(database.lookup_canon_depth(2).unwrap(), Some(2));
+	}
+
+	#[test]
+	fn existing_block_with_different_parent_is_rejected() {
+		let mut database = CountingDatabase { database: test_backend().0, settlements: 0, mislabeled: None };
+		import_operation(&[DummyBlock::new(1, 0), DummyBlock::new(2, 1)])
+			.settle(&mut database).unwrap();
+
+		database.settlements = 0;
+		assert!(import_operation(&[DummyBlock::new(2, 0)]).settle(&mut database).is_err());
+		assert_eq!(database.settlements, 0);
+		assert_eq!(database.block_at(&2).unwrap(), DummyBlock::new(2, 1));
+	}
+
+	#[test]
+	fn racing_identical_commits_settle_once() {
+		let operation = || {
+			let blocks = (1..=50).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+			let mut operation = import_operation(&blocks);
+			operation.set_head = Some(50);
+			operation
+		};
+		let mut single = CountingDatabase { database: test_backend().0, settlements: 0, mislabeled: None };
+		operation().settle(&mut single).unwrap();
+
+		let database = Arc::new(Mutex::new(
+			CountingDatabase { database: test_backend().0, settlements: 0, mislabeled: None }
+		));
+		let barrier = Arc::new(Barrier::new(8));
+		let threads = (0..8).map(|_| {
+			let database = database.clone();
+			let barrier = barrier.clone();
+			thread::spawn(move || {
+				let operation = operation();
+				barrier.wait();
+				operation.settle(&mut *database.lock().unwrap()).unwrap();
+			})
+		}).collect::<Vec<_>>();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		// Only the first of the racing commits settles anything.
+		assert_eq!(database.lock().unwrap().settlements, single.settlements);
+	}
+
+	#[test]
+	fn capacity_hinted_backend_imports_long_chain() {
+		let mut backend = MemoryBackend::with_capacity(DummyBlock::genesis(), Default::default(), 10_000);
+		let blocks = (1..=10_000).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &blocks, true).unwrap();
+
+		assert_eq!(backend.head(), 10_000);
+		assert_eq!(backend.depth_at(&10_000).unwrap(), 10_000);
+		assert_eq!(backend.lookup_canon_depth(5_000).unwrap(), Some(5_000));
+	}
+
+	#[test]
+	fn all_blocks_at_depth_are_returned() {
+		let backend = shared_test_backend();
+		import_shared_chain(&backend, &[
+			DummyBlock::new(1, 0),
+			DummyBlock::new(2, 1),
+			DummyBlock::new(3, 2),
+			DummyBlock::new(4, 1),
+			DummyBlock::new(5, 4),
+		], false).unwrap();
+
+		let mut blocks = backend.blocks_at_depth(3).unwrap();
+		blocks.sort();
+		assert_eq!(blocks, vec![3, 5]);
+
+		let mut blocks = backend.read_only().blocks_at_depth(3).unwrap();
+		blocks.sort();
+		assert_eq!(blocks, vec![3, 5]);
+		assert!(backend.blocks_at_depth(4).unwrap().is_empty());
+	}
+
+	#[test]
+	fn deep_clone_is_independent() {
+		let backend = shared_test_backend();
+		import_shared_chain(&backend, &[DummyBlock::new(1, 0)], true).unwrap();
+		let copy = backend.clone_deep();
+
+		import_shared_chain(&backend, &[DummyBlock::new(2, 1)], true).unwrap();
+		import_shared_chain(&copy, &[DummyBlock::new(3, 1)], true).unwrap();
+
+		assert_eq!(backend.head(), 2);
+		assert!(!backend.contains(&3).unwrap());
+		assert_eq!(copy.head(), 3);
+		assert!(!copy.contains(&2).unwrap());
+		assert!(copy.contains(&1).unwrap());
+	}
+
+	#[test]
+	fn flush_is_no_op() {
+		let backend = shared_test_backend();
+		import_shared_chain(&backend, &[DummyBlock::new(1, 0)], true).unwrap();
+
+		SharedCommittable::flush(&backend).unwrap();
+		assert_eq!(backend.head(), 1);
+		assert_eq!(backend.leaves().unwrap(), vec![1]);
+	}
+
+	#[test]
+	fn debug_summarizes_backend() {
+		let mut backend = test_backend();
+		let blocks = (1..=3).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &blocks, true).unwrap();
+		import_chain(&mut backend, &[DummyBlock::new(4, 1)], false).unwrap();
+
+		assert_eq!(
+			format!("{:?}", backend),
+			"MemoryBackend(MemoryDatabase { genesis: 0, head: 3, blocks: 5, canon_depth: 3, \
+				auxiliaries: 0, fork_points: 1 })",
+		);
+	}
+
+	/// Functional counter, adding the block id to the counter. Blocks with
+	/// odd ids are invalid.
+	struct FunctionalCounter;
+
+	impl FunctionalExecutor for FunctionalCounter {
+		type Error = CounterError;
+		type Block = DummyBlock;
+		type State = KeyValueMemoryState;
+
+		fn apply(
+			&self,
+			block: &DummyBlock,
+			mut state: KeyValueMemoryState,
+		) -> Result<KeyValueMemoryState, CounterError> {
+			if block.id % 2 == 1 {
+				return Err(CounterError)
+			}
+
+			let value = counter(&mut state) + block.id;
+			let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+			externalities.write_storage(b"counter".to_vec(), value.to_le_bytes().to_vec());
+			Ok(state)
+		}
+	}
+
+	#[test]
+	fn functional_executor_imports_through_adapter() {
+		let backend = shared_test_backend();
+		let executor = FunctionalAdapter(FunctionalCounter);
+
+		assert!(backend.import_and_set_head(&executor, DummyBlock::new(2, 0)).unwrap());
+		assert!(backend.import_and_set_head(&executor, DummyBlock::new(4, 2)).unwrap());
+		assert!(backend.import_and_set_head(&executor, DummyBlock::new(5, 4)).is_err());
+
+		assert_eq!(backend.head(), 4);
+		assert!(!backend.contains(&5).unwrap());
+		assert_eq!(counter(&mut backend.state_at(&4).unwrap()), 6);
+	}
+
+	#[test]
+	fn head_check_is_serialized_with_external_importers() {
+		let lock = ImportLock::new();
+		let backend = shared_test_backend().with_import_lock(lock.clone());
+
+		// Another importer holds the shared lock while the head is checked.
+		let mut action = ImportAction::new(&backend, lock.lock());
+		let importing = {
+			let backend = backend.clone();
+			thread::spawn(move || {
+				backend.import_and_set_head(&CounterExecutor::default(), DummyBlock::new(10, 0))
+					.expect("Block is valid; qed")
+			})
+		};
+		thread::sleep(Duration::from_millis(50));
+		action.import_block(DummyBlock::new(1, 0), Default::default());
+		action.import_block(DummyBlock::new(2, 1), Default::default());
+		action.set_head(2);
+		action.commit().unwrap();
+
+		assert!(!importing.join().unwrap());
+		assert_eq!(backend.head(), 2);
+		assert!(backend.contains(&10).unwrap());
 	}
 }
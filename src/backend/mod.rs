@@ -5,32 +5,92 @@ mod route;
 mod traits;
 mod operation;
 mod state;
+mod read_only;
+mod overlay;
 
-pub use self::memory::{MemoryBackend, MemoryDatabase, SharedMemoryBackend, Error as MemoryError};
-pub use self::route::{tree_route, TreeRoute};
-pub use self::operation::{BlockData, ImportOperation, Operation};
-pub use self::traits::{Store, ChainQuery, ChainSettlement, OperationError, Committable, SharedCommittable};
-pub use self::state::KeyValueMemoryState;
+pub use self::memory::{MemoryBackend, MemoryDatabase, SharedMemoryBackend, BatchConfig, Error as MemoryError};
+pub use self::route::{tree_route, tree_route_bounded, ancestors_bounded, TreeRoute, Ancestry};
+pub use self::operation::{BlockData, ImportOperation, LazyImportOperation, Operation, OperationBuilder};
+pub use self::traits::{Store, ChainQuery, ChainSettlement, OperationError, Committable, SharedCommittable, AuxiliaryKey, AuxiliaryEntry, BlockPath};
+pub use self::state::{StateMap, GenericState, KeyValueMemoryState, OrderedMemoryState, CowState};
+pub use self::read_only::ReadOnlyBackend;
+pub use self::overlay::OverlayedExternalities;
 
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, LockResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::warn;
+
+/// Unwrap a lock result. If the lock is poisoned and `recover_poison` is
+/// set, continue with the inner guard.
+///
+/// Recovery only guarantees memory safety. Operations validate everything
+/// before changing the backend, so a rejected operation leaves no partial
+/// changes, but a panic while an operation is being settled may.
+pub(crate) fn lock_or_recover<G>(result: LockResult<G>, recover_poison: bool) -> G {
+	match result {
+		Ok(guard) => guard,
+		Err(poison) => {
+			if !recover_poison {
+				panic!("Lock is poisoned");
+			}
+
+			warn!("Lock is poisoned, recovering");
+			poison.into_inner()
+		},
+	}
+}
 
 /// Standalone import lock.
-pub struct ImportLock(Arc<Mutex<()>>);
+pub struct ImportLock(Arc<Mutex<()>>, Arc<AtomicBool>);
 
 impl Clone for ImportLock {
 	fn clone(&self) -> Self {
-		Self(self.0.clone())
+		Self(self.0.clone(), self.1.clone())
 	}
 }
 
 impl ImportLock {
 	/// Create a new import lock.
 	pub fn new() -> Self {
-		Self(Arc::new(Mutex::new(())))
+		Self(Arc::new(Mutex::new(())), Arc::new(AtomicBool::new(false)))
+	}
+
+	/// Set whether to recover from a poisoned lock instead of panicking.
+	/// The setting is shared with all clones of the lock.
+	pub fn with_recover_poison(self, recover_poison: bool) -> Self {
+		self.1.store(recover_poison, Ordering::SeqCst);
+		self
+	}
+
+	/// Whether a poisoned lock is recovered instead of panicking.
+	pub fn recover_poison(&self) -> bool {
+		self.1.load(Ordering::SeqCst)
 	}
 
 	/// Lock the import.
 	pub fn lock(&self) -> MutexGuard<()> {
-		self.0.lock().expect("Lock is poisoned")
+		lock_or_recover(self.0.lock(), self.recover_poison())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+
+	#[test]
+	fn recover_poison_is_shared_with_clones() {
+		let lock = ImportLock::new();
+		let clone = lock.clone();
+		let lock = lock.with_recover_poison(true);
+
+		let panicking = clone.clone();
+		assert!(thread::spawn(move || {
+			let _guard = panicking.lock();
+			panic!("Import panics while holding the lock");
+		}).join().is_err());
+
+		drop(clone.lock());
+		drop(lock.lock());
 	}
 }
@@ -1,8 +1,11 @@
+use std::fmt;
 use std::collections::HashMap;
 use crate::{Block, Auxiliary};
-use crate::backend::{tree_route, Store, ChainQuery, ChainSettlement, OperationError};
+use crate::backend::route::tree_route_by;
+use crate::backend::{TreeRoute, Store, ChainQuery, ChainSettlement, OperationError};
 
 /// Representing raw block data.
+#[derive(Clone)]
 pub struct BlockData<B: Block, S> {
 	/// Block of the data.
 	pub block: B,
@@ -14,6 +17,22 @@ pub struct BlockData<B: Block, S> {
 	pub children: Vec<B::Identifier>,
 	/// Whether the block is on the canonical chain.
 	pub is_canon: bool,
+	/// State root of the block, if known.
+	pub state_root: Option<Vec<u8>>,
+}
+
+impl<B: Block + fmt::Debug, S> fmt::Debug for BlockData<B, S> where
+	B::Identifier: fmt::Debug,
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("BlockData")
+			.field("block", &self.block)
+			.field("depth", &self.depth)
+			.field("children", &self.children)
+			.field("is_canon", &self.is_canon)
+			.field("state_root", &self.state_root)
+			.finish()
+	}
 }
 
 /// Import operation.
@@ -24,38 +43,206 @@ pub struct ImportOperation<B, S> {
 	pub state: S,
 }
 
+impl<B: fmt::Debug, S> fmt::Debug for ImportOperation<B, S> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ImportOperation")
+			.field("block", &self.block)
+			.finish()
+	}
+}
+
+/// Lazy import operation. The state is computed from the parent state at
+/// settlement time, instead of being materialized up-front.
+pub struct LazyImportOperation<B, S> {
+	/// Block to be imported.
+	pub block: B,
+	/// Function computing the state of the block from its parent state.
+	pub state: Box<dyn FnOnce(&S) -> S + Send>,
+}
+
+enum PendingImport<B, S> {
+	Eager(ImportOperation<B, S>),
+	Lazy(LazyImportOperation<B, S>),
+}
+
+impl<B, S> PendingImport<B, S> {
+	fn block(&self) -> &B {
+		match self {
+			PendingImport::Eager(op) => &op.block,
+			PendingImport::Lazy(op) => &op.block,
+		}
+	}
+}
+
 /// Operation for a backend.
 pub struct Operation<B: Block, S, A: Auxiliary<B>> {
 	/// Import operation.
 	pub import_block: Vec<ImportOperation<B, S>>,
+	/// Lazy import operation.
+	pub import_lazy_block: Vec<LazyImportOperation<B, S>>,
 	/// Set head operation.
 	pub set_head: Option<B::Identifier>,
+	/// State roots insertion operation.
+	pub insert_state_roots: Vec<(B::Identifier, Vec<u8>)>,
 	/// Auxiliaries insertion operation.
 	pub insert_auxiliaries: Vec<A>,
 	/// Auxiliaries removal operation.
 	pub remove_auxiliaries: Vec<A::Key>,
 }
 
+impl<B: Block, S, A: Auxiliary<B>> fmt::Debug for Operation<B, S, A> where
+	B::Identifier: fmt::Debug,
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Operation")
+			.field("import_block", &self.import_block.iter()
+				.map(|op| op.block.id()).collect::<Vec<_>>())
+			.field("import_lazy_block", &self.import_lazy_block.iter()
+				.map(|op| op.block.id()).collect::<Vec<_>>())
+			.field("set_head", &self.set_head)
+			.field("insert_state_roots", &self.insert_state_roots.iter()
+				.map(|(id, _)| id).collect::<Vec<_>>())
+			.field("insert_auxiliaries", &self.insert_auxiliaries.len())
+			.field("remove_auxiliaries", &self.remove_auxiliaries.len())
+			.finish()
+	}
+}
+
 impl<B: Block, S, A: Auxiliary<B>> Default for Operation<B, S, A> {
 	fn default() -> Self {
 		Self {
 			import_block: Vec::new(),
+			import_lazy_block: Vec::new(),
 			set_head: None,
+			insert_state_roots: Vec::new(),
 			insert_auxiliaries: Vec::new(),
 			remove_auxiliaries: Vec::new(),
 		}
 	}
 }
 
+/// Builder for operation.
+pub struct OperationBuilder<B: Block, S, A: Auxiliary<B>> {
+	operation: Operation<B, S, A>,
+}
+
+impl<B: Block, S, A: Auxiliary<B>> Default for OperationBuilder<B, S, A> {
+	fn default() -> Self {
+		Self {
+			operation: Default::default(),
+		}
+	}
+}
+
+impl<B: Block, S, A: Auxiliary<B>> OperationBuilder<B, S, A> {
+	/// Create a new operation builder.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Import a new block.
+	pub fn import(mut self, block: B, state: S) -> Self {
+		self.operation.import_block.push(ImportOperation { block, state });
+		self
+	}
+
+	/// Import a new block, whose state is computed from its parent state
+	/// at settlement.
+	pub fn import_lazy<F>(mut self, block: B, state: F) -> Self where
+		F: FnOnce(&S) -> S + Send + 'static,
+	{
+		self.operation.import_lazy_block.push(LazyImportOperation {
+			block,
+			state: Box::new(state),
+		});
+		self
+	}
+
+	/// Set head to given hash.
+	pub fn set_head(mut self, head: B::Identifier) -> Self {
+		self.operation.set_head = Some(head);
+		self
+	}
+
+	/// Set state root of a block.
+	pub fn set_state_root(mut self, id: B::Identifier, state_root: Vec<u8>) -> Self {
+		self.operation.insert_state_roots.push((id, state_root));
+		self
+	}
+
+	/// Insert auxiliary value.
+	pub fn insert_aux(mut self, aux: A) -> Self {
+		self.operation.insert_auxiliaries.push(aux);
+		self
+	}
+
+	/// Remove auxiliary value.
+	pub fn remove_aux(mut self, aux_key: A::Key) -> Self {
+		self.operation.remove_auxiliaries.push(aux_key);
+		self
+	}
+
+	/// Build the operation.
+	pub fn build(self) -> Operation<B, S, A> {
+		self.operation
+	}
+}
+
 impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
-	/// Settle the current operation.
+	/// Create a new operation builder.
+	pub fn builder() -> OperationBuilder<B, S, A> {
+		OperationBuilder::new()
+	}
+
+	/// Merge a later operation into this one, so that settling the merged
+	/// operation has the same result as settling both in order.
+	///
+	/// Block imports, state roots and auxiliary changes are appended. A
+	/// later head replaces an earlier one. A later auxiliary removal
+	/// cancels earlier insertions of the same key, while a later insertion
+	/// takes precedence over earlier removals.
+	pub fn merge(&mut self, other: Self) {
+		self.import_block.extend(other.import_block);
+		self.import_lazy_block.extend(other.import_lazy_block);
+		if other.set_head.is_some() {
+			self.set_head = other.set_head;
+		}
+		self.insert_state_roots.extend(other.insert_state_roots);
+
+		let removed = &other.remove_auxiliaries;
+		self.insert_auxiliaries.retain(|aux| !removed.contains(&aux.key()));
+		self.remove_auxiliaries.extend(other.remove_auxiliaries);
+		self.insert_auxiliaries.extend(other.insert_auxiliaries);
+	}
+
+	/// Settle the current operation. Blocks that already exist in the
+	/// backend, including the genesis block, are skipped, and setting head
+	/// to the current head is a no-op, so that settling the same operation
+	/// twice is cheap. Importing an existing block id with a different
+	/// parent, or any other parentless block, fails.
 	pub fn settle<Ba>(self, backend: &mut Ba) -> Result<(), Ba::Error> where
 		Ba: ChainQuery + ChainSettlement + Store<Block=B, State=S, Auxiliary=A>,
 		Ba::Error: OperationError,
+	{
+		self.settle_with_route(backend)?;
+		Ok(())
+	}
+
+	/// Settle the current operation, returning the tree route from the old
+	/// head to the new head, if the head has changed.
+	pub fn settle_with_route<Ba>(
+		self,
+		backend: &mut Ba
+	) -> Result<Option<TreeRoute<B>>, Ba::Error> where
+		Ba: ChainQuery + ChainSettlement + Store<Block=B, State=S, Auxiliary=A>,
+		Ba::Error: OperationError,
 	{
 		let mut parent_ides = HashMap::new();
 		let mut importing: HashMap<<Ba::Block as Block>::Identifier, BlockData<Ba::Block, Ba::State>> = HashMap::new();
-		let mut verifying = self.import_block;
+		let mut verifying = self.import_block.into_iter()
+			.map(PendingImport::Eager)
+			.chain(self.import_lazy_block.into_iter().map(PendingImport::Lazy))
+			.collect::<Vec<_>>();
 
 		// Do precheck to make sure the import self is valid.
 		loop {
@@ -63,32 +250,59 @@ impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
 			let mut next_verifying = Vec::new();
 
 			for op in verifying {
-				let parent_depth = match op.block.parent_id() {
-					Some(parent_id) => {
-						if backend.contains(&parent_id)? {
-							Some(backend.depth_at(&parent_id)?)
-						} else if importing.contains_key(&parent_id) {
-							importing.get(&parent_id)
-								.map(|data| data.depth)
-						} else {
-							None
-						}
+				// Re-importing the existing genesis is a no-op, but any other
+				// parentless block would be a second genesis.
+				let parent_id = match op.block().parent_id() {
+					Some(parent_id) => parent_id,
+					None if op.block().id() == backend.genesis() => {
+						progress = true;
+						continue
 					},
 					None => return Err(Ba::Error::block_is_genesis()),
 				};
+
+				// An existing block is skipped, as long as it is the same
+				// block. Its state is not compared, as states have no
+				// equality; it follows from executing the block.
+				if backend.contains(&op.block().id())? {
+					if backend.block_at(&op.block().id())?.parent_id() != Some(parent_id) {
+						return Err(Ba::Error::invalid_operation())
+					}
+
+					progress = true;
+					continue
+				}
+
+				let parent_depth = if backend.contains(&parent_id)? {
+					Some(backend.depth_at(&parent_id)?)
+				} else if importing.contains_key(&parent_id) {
+					importing.get(&parent_id)
+						.map(|data| data.depth)
+				} else {
+					None
+				};
 				let depth = parent_depth.map(|d| d + 1);
 
 				if let Some(depth) = depth {
 					progress = true;
-					if let Some(parent_id) = op.block.parent_id() {
-						parent_ides.insert(op.block.id(), parent_id);
-					}
-					importing.insert(op.block.id(), BlockData {
-						block: op.block,
-						state: op.state,
+					let (block, state) = match op {
+						PendingImport::Eager(op) => (op.block, op.state),
+						PendingImport::Lazy(op) => {
+							let state = match importing.get(&parent_id) {
+								Some(data) => (op.state)(&data.state),
+								None => (op.state)(&backend.state_at(&parent_id)?),
+							};
+							(op.block, state)
+						},
+					};
+					parent_ides.insert(block.id(), parent_id);
+					importing.insert(block.id(), BlockData {
+						block,
+						state,
 						depth,
 						children: Vec::new(),
 						is_canon: false,
+						state_root: None,
 					});
 				} else {
 					next_verifying.push(op)
@@ -116,6 +330,13 @@ impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
 			}
 		}
 
+		// Do precheck to make sure state roots are for existing blocks.
+		for (id, _) in &self.insert_state_roots {
+			if !(backend.contains(id)? || importing.contains_key(id)) {
+				return Err(Ba::Error::invalid_operation());
+			}
+		}
+
 		// Do precheck to make sure auxiliary is valid.
 		for aux in &self.insert_auxiliaries {
 			for id in aux.associated() {
@@ -125,6 +346,37 @@ impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
 			}
 		}
 
+		// Compute the route to the new head before changing anything, so
+		// that a rejected operation leaves the backend untouched.
+		let mut head_change = None;
+		if let Some(new_head) = self.set_head.filter(|new_head| *new_head != backend.head()) {
+			let route = tree_route_by(&backend.head(), &new_head, None, |id| {
+				match importing.get(id) {
+					Some(data) => Ok((data.block.clone(), data.depth)),
+					None => Ok((backend.block_at(id)?, backend.depth_at(id)?)),
+				}
+			})?.expect("Route is not bounded; qed");
+
+			// The new head must end the route, either as the last enacted
+			// block, or as the common block when nothing is enacted.
+			// Otherwise canonicality would be set for the wrong branch.
+			let route_end = route.enacted().last().unwrap_or_else(|| route.common_block());
+			if *route_end != new_head {
+				return Err(Ba::Error::invalid_operation())
+			}
+
+			let with_depths = |ids: &[B::Identifier]| {
+				ids.iter().map(|id| match importing.get(id) {
+					Some(data) => Ok((id.clone(), data.depth)),
+					None => Ok((id.clone(), backend.depth_at(id)?)),
+				}).collect::<Result<Vec<_>, Ba::Error>>()
+			};
+			let retracted = with_depths(route.retracted())?;
+			let enacted = with_depths(route.enacted())?;
+
+			head_change = Some((new_head, route, retracted, enacted));
+		}
+
 		for (id, data) in importing {
 			backend.insert_block(
 				id, data.block, data.state, data.depth, data.children, data.is_canon
@@ -136,25 +388,24 @@ impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
 			backend.push_child(parent_id, id);
 		}
 
-		if let Some(new_head) = self.set_head {
-			let route = tree_route(backend, &backend.head(), &new_head)
-				.expect("Blocks are checked to exist or importing; qed");
+		for (id, state_root) in self.insert_state_roots {
+			backend.set_state_root(id, state_root);
+		}
 
-			for id in route.retracted() {
-				backend.set_canon(id.clone(), false);
-				let depth = backend.depth_at(id)
-					.expect("Block is fetched from tree_route; it must exist; qed");
+		let mut head_route = None;
+		if let Some((new_head, route, retracted, enacted)) = head_change {
+			for (id, depth) in retracted {
+				backend.set_canon(id, false);
 				backend.remove_canon_depth_mapping(&depth);
 			}
 
-			for id in route.enacted() {
+			for (id, depth) in enacted {
 				backend.set_canon(id.clone(), true);
-				let depth = backend.depth_at(id)
-					.expect("Block is fetched from tree_route; it must exist; qed");
-				backend.insert_canon_depth_mapping(depth, id.clone());
+				backend.insert_canon_depth_mapping(depth, id);
 			}
 
 			backend.set_head(new_head);
+			head_route = Some(route);
 		}
 
 		for aux_key in self.remove_auxiliaries {
@@ -165,6 +416,147 @@ impl<B: Block, S, A: Auxiliary<B>> Operation<B, S, A> {
 			backend.insert_auxiliary(aux.key(), aux);
 		}
 
-		Ok(())
+		Ok(head_route)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::{Committable, KeyValueMemoryState, MemoryBackend, MemoryError};
+	use crate::test_support::{DummyBlock, Note, import_chain, test_backend};
+
+	#[test]
+	fn built_operation_equals_constructed_operation() {
+		let built: Operation<DummyBlock, KeyValueMemoryState, ()> = Operation::builder()
+			.import(DummyBlock::new(1, 0), Default::default())
+			.import(DummyBlock::new(2, 1), Default::default())
+			.set_state_root(2, vec![2])
+			.set_head(2)
+			.build();
+		let constructed = Operation {
+			import_block: vec![
+				ImportOperation { block: DummyBlock::new(1, 0), state: Default::default() },
+				ImportOperation { block: DummyBlock::new(2, 1), state: Default::default() },
+			],
+			set_head: Some(2),
+			insert_state_roots: vec![(2, vec![2])],
+			..Default::default()
+		};
+		assert_eq!(format!("{:?}", built), format!("{:?}", constructed));
+
+		let mut built_backend = test_backend();
+		built_backend.commit(built).unwrap();
+		let mut constructed_backend = test_backend();
+		constructed_backend.commit(constructed).unwrap();
+
+		for backend in &[built_backend, constructed_backend] {
+			assert_eq!(backend.head(), 2);
+			assert_eq!(backend.lookup_canon_depth(2).unwrap(), Some(2));
+			assert_eq!(backend.state_root_at(&2).unwrap(), Some(vec![2]));
+		}
+	}
+
+	#[test]
+	fn malformed_head_is_rejected_without_changes() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0)], true).unwrap();
+
+		let operation = Operation::builder()
+			.import(DummyBlock::new(2, 1), Default::default())
+			.import(DummyBlock::new(3, 2), Default::default())
+			.set_head(4)
+			.build();
+		match backend.commit(operation) {
+			Err(MemoryError::InvalidOperation) => (),
+			other => panic!("Expected invalid operation, got {:?}", other),
+		}
+
+		assert_eq!(backend.head(), 1);
+		assert!(!backend.contains(&2).unwrap());
+		assert_eq!(backend.lookup_canon_depth(1).unwrap(), Some(1));
+		assert_eq!(backend.lookup_canon_depth(2).unwrap(), None);
+	}
+
+	type NoteOperation = Operation<DummyBlock, KeyValueMemoryState, Note>;
+
+	fn note_operations() -> Vec<NoteOperation> {
+		vec![
+			Operation::builder()
+				.import(DummyBlock::new(1, 0), Default::default())
+				.import(DummyBlock::new(2, 1), Default::default())
+				.insert_aux(Note { key: 1, value: 10 })
+				.insert_aux(Note { key: 2, value: 20 })
+				.set_head(2)
+				.build(),
+			Operation::builder()
+				.import(DummyBlock::new(3, 1), Default::default())
+				.remove_aux(1)
+				.insert_aux(Note { key: 3, value: 30 })
+				.set_head(3)
+				.build(),
+			Operation::builder()
+				.import(DummyBlock::new(4, 2), Default::default())
+				.insert_aux(Note { key: 1, value: 100 })
+				.set_head(4)
+				.build(),
+		]
+	}
+
+	#[test]
+	fn merged_operation_settles_like_sequential_operations() {
+		let new_backend = || MemoryBackend::<DummyBlock, Note, KeyValueMemoryState>::new_with_genesis(
+			DummyBlock::genesis(),
+			Default::default(),
+		);
+
+		let mut sequential = new_backend();
+		for operation in note_operations() {
+			sequential.commit(operation).unwrap();
+		}
+
+		let mut merged = new_backend();
+		let mut operations = note_operations().into_iter();
+		let mut operation = operations.next().unwrap();
+		for other in operations {
+			operation.merge(other);
+		}
+		merged.commit(operation).unwrap();
+
+		for backend in &[sequential, merged] {
+			assert_eq!(backend.head(), 4);
+			assert!(backend.contains(&3).unwrap());
+			assert!(!backend.is_canon(&3).unwrap());
+			assert_eq!(backend.lookup_canon_depth(2).unwrap(), Some(2));
+
+			let mut auxiliaries = backend.auxiliaries().unwrap();
+			auxiliaries.sort_by_key(|(key, _)| *key);
+			assert_eq!(auxiliaries.into_iter().map(|(_, note)| note.value).collect::<Vec<_>>(), vec![100, 20, 30]);
+		}
+	}
+
+	#[test]
+	fn existing_genesis_is_skipped_and_foreign_genesis_rejected() {
+		let mut backend = test_backend();
+		let operation = Operation::builder()
+			.import(DummyBlock::genesis(), Default::default())
+			.import(DummyBlock::new(1, 0), Default::default())
+			.set_head(1)
+			.build();
+		backend.commit(operation).unwrap();
+		assert_eq!(backend.head(), 1);
+		assert_eq!(backend.genesis(), 0);
+
+		let foreign_genesis = DummyBlock { id: 100, parent: None, spec_version: 0 };
+		let operation = Operation::builder()
+			.import(DummyBlock::new(2, 1), Default::default())
+			.import(foreign_genesis, Default::default())
+			.build();
+		match backend.commit(operation) {
+			Err(MemoryError::IsGenesis) => (),
+			other => panic!("Expected foreign genesis to be rejected, got {:?}", other),
+		}
+		assert!(!backend.contains(&2).unwrap());
+		assert!(!backend.contains(&100).unwrap());
 	}
 }
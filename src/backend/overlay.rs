@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use crate::StorageExternalities;
+
+type Changes = HashMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Externalities with nested storage transactions on top of a base.
+///
+/// Without an open transaction, writes go directly to the base. Inside a
+/// transaction, writes are kept in an overlay, and reads consult the
+/// overlays from the innermost transaction outwards before the base.
+pub struct OverlayedExternalities<'a, E: ?Sized, Error> {
+	base: &'a mut E,
+	transactions: Vec<Changes>,
+	_marker: PhantomData<Error>,
+}
+
+impl<'a, E: StorageExternalities<Error> + ?Sized, Error> OverlayedExternalities<'a, E, Error> {
+	/// Create new overlayed externalities over a base.
+	pub fn new(base: &'a mut E) -> Self {
+		Self {
+			base,
+			transactions: Vec::new(),
+			_marker: PhantomData,
+		}
+	}
+
+	/// Number of currently open transactions.
+	pub fn transaction_depth(&self) -> usize {
+		self.transactions.len()
+	}
+
+	/// Start a new, possibly nested, transaction.
+	pub fn start_transaction(&mut self) {
+		self.transactions.push(HashMap::new());
+	}
+
+	/// Commit the innermost transaction into its enclosing transaction, or
+	/// into the base if it is the outermost one. Returns false if no
+	/// transaction is open.
+	pub fn commit_transaction(&mut self) -> bool {
+		let changes = match self.transactions.pop() {
+			Some(changes) => changes,
+			None => return false,
+		};
+
+		match self.transactions.last_mut() {
+			Some(parent) => parent.extend(changes),
+			None => {
+				for (key, value) in changes {
+					match value {
+						Some(value) => self.base.write_storage(key, value),
+						None => self.base.remove_storage(&key),
+					}
+				}
+			},
+		}
+
+		true
+	}
+
+	/// Discard the innermost transaction. Returns false if no transaction
+	/// is open.
+	pub fn rollback_transaction(&mut self) -> bool {
+		self.transactions.pop().is_some()
+	}
+}
+
+impl<'a, E: StorageExternalities<Error> + ?Sized, Error> StorageExternalities<Error> for OverlayedExternalities<'a, E, Error> {
+	fn read_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		for changes in self.transactions.iter().rev() {
+			if let Some(value) = changes.get(key) {
+				return Ok(value.clone())
+			}
+		}
+
+		self.base.read_storage(key)
+	}
+
+	fn write_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		match self.transactions.last_mut() {
+			Some(changes) => { changes.insert(key, Some(value)); },
+			None => self.base.write_storage(key, value),
+		}
+	}
+
+	fn remove_storage(&mut self, key: &[u8]) {
+		match self.transactions.last_mut() {
+			Some(changes) => { changes.insert(key.to_vec(), None); },
+			None => self.base.remove_storage(key),
+		}
+	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		let mut values = self.base.iter_prefix(prefix)
+			.map(|(key, value)| (key, Some(value)))
+			.collect::<HashMap<_, _>>();
+
+		for changes in &self.transactions {
+			for (key, value) in changes {
+				if key.starts_with(prefix) {
+					values.insert(key.clone(), value.clone());
+				}
+			}
+		}
+
+		Box::new(values.into_iter().filter_map(|(key, value)| value.map(|value| (key, value))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use core::convert::Infallible;
+	use crate::AsExternalities;
+	use crate::backend::KeyValueMemoryState;
+
+	type Base = dyn StorageExternalities<Infallible>;
+
+	fn read(externalities: &dyn StorageExternalities<Infallible>, key: &[u8]) -> Option<Vec<u8>> {
+		match externalities.read_storage(key) {
+			Ok(value) => value,
+			Err(e) => match e { },
+		}
+	}
+
+	#[test]
+	fn rolled_back_writes_leave_base_unchanged() {
+		let mut state = KeyValueMemoryState::default();
+		let base: &mut Base = state.as_externalities();
+		base.write_storage(b"a".to_vec(), vec![1]);
+
+		let mut overlay = OverlayedExternalities::new(base);
+		overlay.start_transaction();
+		overlay.write_storage(b"a".to_vec(), vec![2]);
+		overlay.write_storage(b"b".to_vec(), vec![3]);
+		assert_eq!(read(&overlay, b"a"), Some(vec![2]));
+		assert!(overlay.rollback_transaction());
+		assert_eq!(read(&overlay, b"a"), Some(vec![1]));
+		assert!(!overlay.rollback_transaction());
+
+		let base: &mut Base = state.as_externalities();
+		assert_eq!(read(base, b"a"), Some(vec![1]));
+		assert_eq!(read(base, b"b"), None);
+	}
+
+	#[test]
+	fn nested_transactions_commit_outwards() {
+		let mut state = KeyValueMemoryState::default();
+		let mut overlay = OverlayedExternalities::<Base, _>::new(state.as_externalities());
+
+		overlay.start_transaction();
+		overlay.write_storage(b"a".to_vec(), vec![1]);
+		overlay.start_transaction();
+		overlay.write_storage(b"c".to_vec(), vec![2]);
+		overlay.remove_storage(b"a");
+		assert_eq!(read(&overlay, b"a"), None);
+		assert!(overlay.rollback_transaction());
+		assert_eq!(overlay.transaction_depth(), 1);
+		assert!(overlay.commit_transaction());
+
+		let base: &mut Base = state.as_externalities();
+		assert_eq!(read(base, b"a"), Some(vec![1]));
+		assert_eq!(read(base, b"c"), None);
+	}
+}
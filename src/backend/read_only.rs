@@ -0,0 +1,150 @@
+use crate::{Block, Auxiliary};
+use crate::backend::{Store, ChainQuery};
+
+/// Read-only proxy of a backend. It only exposes chain queries, and
+/// deliberately does not implement any committable trait, so that it can
+/// be handed to code that must not mutate the chain.
+///
+/// Committing through the proxy does not compile:
+///
+/// ```compile_fail
+/// use blockchain::Block;
+/// use blockchain::backend::{SharedMemoryBackend, SharedCommittable, KeyValueMemoryState};
+///
+/// #[derive(Clone)]
+/// struct NumberBlock(u64);
+///
+/// impl Block for NumberBlock {
+///     type Identifier = u64;
+///
+///     fn id(&self) -> u64 { self.0 }
+///     fn parent_id(&self) -> Option<u64> { self.0.checked_sub(1) }
+/// }
+///
+/// let backend = SharedMemoryBackend::<NumberBlock, (), KeyValueMemoryState>::new_with_genesis(
+///     NumberBlock(0),
+///     Default::default(),
+/// );
+/// backend.read_only().commit(Default::default()).unwrap();
+/// ```
+pub struct ReadOnlyBackend<Ba>(Ba);
+
+impl<Ba> ReadOnlyBackend<Ba> {
+	/// Create a new read-only proxy of the given backend.
+	pub fn new(backend: Ba) -> Self {
+		Self(backend)
+	}
+}
+
+impl<Ba: Clone> Clone for ReadOnlyBackend<Ba> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+
+impl<Ba: Store> Store for ReadOnlyBackend<Ba> {
+	type Block = Ba::Block;
+	type State = Ba::State;
+	type Auxiliary = Ba::Auxiliary;
+	type Error = Ba::Error;
+}
+
+impl<Ba: ChainQuery> ChainQuery for ReadOnlyBackend<Ba> {
+	fn genesis(&self) -> <Self::Block as Block>::Identifier {
+		self.0.genesis()
+	}
+	fn head(&self) -> <Self::Block as Block>::Identifier {
+		self.0.head()
+	}
+	fn contains(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		self.0.contains(hash)
+	}
+	fn is_canon(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		self.0.is_canon(hash)
+	}
+	fn lookup_canon_depth(
+		&self,
+		depth: usize,
+	) -> Result<Option<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.lookup_canon_depth(depth)
+	}
+	fn auxiliary(
+		&self,
+		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
+	) -> Result<Option<Self::Auxiliary>, Self::Error> {
+		self.0.auxiliary(key)
+	}
+	fn auxiliaries(
+		&self,
+	) -> Result<Vec<(<Self::Auxiliary as Auxiliary<Self::Block>>::Key, Self::Auxiliary)>, Self::Error> {
+		self.0.auxiliaries()
+	}
+	fn depth_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<usize, Self::Error> {
+		self.0.depth_at(hash)
+	}
+	fn children_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.children_at(hash)
+	}
+	fn children_paginated(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		offset: usize,
+		limit: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.children_paginated(hash, offset, limit)
+	}
+	fn state_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::State, Self::Error> {
+		self.0.state_at(hash)
+	}
+
+	fn with_state_at<R, F: FnOnce(&Self::State) -> R>(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		f: F,
+	) -> Result<R, Self::Error> {
+		self.0.with_state_at(hash, f)
+	}
+	fn block_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Self::Block, Self::Error> {
+		self.0.block_at(hash)
+	}
+	fn state_root_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.0.state_root_at(hash)
+	}
+	fn blocks_at_depth(
+		&self,
+		depth: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.blocks_at_depth(depth)
+	}
+	fn fork_points(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.fork_points()
+	}
+	fn leaves(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		self.0.leaves()
+	}
+}
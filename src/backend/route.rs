@@ -18,7 +18,7 @@
 // along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::Block;
-use crate::backend::ChainQuery;
+use crate::backend::{ChainQuery, OperationError};
 
 /// A tree-route from one block to another in the chain.
 ///
@@ -42,12 +42,33 @@ use crate::backend::ChainQuery;
 /// Tree route from C to E2. Retracted empty. Common is C, enacted [E1, E2]
 /// C -> E1 -> E2
 /// ```
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(
+	serialize = "B::Identifier: serde::Serialize",
+	deserialize = "B::Identifier: serde::Deserialize<'de>",
+)))]
 pub struct TreeRoute<B: Block> {
 	route: Vec<B::Identifier>,
 	pivot: usize,
 }
 
 impl<B: Block> TreeRoute<B> {
+	/// Create a tree route from its raw parts: the route from the retracted
+	/// end, through the common block at `pivot`, to the enacted end. None
+	/// if `pivot` is out of bounds.
+	pub fn from_parts(route: Vec<B::Identifier>, pivot: usize) -> Option<Self> {
+		if pivot >= route.len() {
+			return None
+		}
+
+		Some(Self { route, pivot })
+	}
+
+	/// Split the tree route into its raw parts, the route and the pivot.
+	pub fn into_parts(self) -> (Vec<B::Identifier>, usize) {
+		(self.route, self.pivot)
+	}
+
 	/// Get a slice of all retracted blocks in reverse order (towards common ancestor)
 	pub fn retracted(&self) -> &[B::Identifier] {
 		&self.route[..self.pivot]
@@ -73,43 +94,93 @@ pub fn tree_route<Ba: ChainQuery>(
 	from_id: &<Ba::Block as Block>::Identifier,
 	to_id: &<Ba::Block as Block>::Identifier,
 ) -> Result<TreeRoute<Ba::Block>, Ba::Error> {
-	let mut from = backend.block_at(from_id)?;
-	let mut to = backend.block_at(to_id)?;
+	Ok(tree_route_with_limit(backend, from_id, to_id, None)?
+	   .expect("Route without step limit is always computed; qed"))
+}
+
+/// Compute a tree-route between two blocks, visiting at most `max_steps`
+/// parent blocks. Returns an error if the bound is exceeded, which
+/// protects against malformed backends causing unbounded work.
+pub fn tree_route_bounded<Ba: ChainQuery>(
+	backend: &Ba,
+	from_id: &<Ba::Block as Block>::Identifier,
+	to_id: &<Ba::Block as Block>::Identifier,
+	max_steps: usize,
+) -> Result<TreeRoute<Ba::Block>, Ba::Error> where
+	Ba::Error: OperationError,
+{
+	tree_route_with_limit(backend, from_id, to_id, Some(max_steps))?
+		.ok_or_else(Ba::Error::route_too_long)
+}
+
+fn tree_route_with_limit<Ba: ChainQuery>(
+	backend: &Ba,
+	from_id: &<Ba::Block as Block>::Identifier,
+	to_id: &<Ba::Block as Block>::Identifier,
+	max_steps: Option<usize>,
+) -> Result<Option<TreeRoute<Ba::Block>>, Ba::Error> {
+	tree_route_by(from_id, to_id, max_steps, |id| Ok((backend.block_at(id)?, backend.depth_at(id)?)))
+}
+
+/// Compute a tree-route between two blocks, looking up each block and its
+/// depth through `fetch`. This allows routing through blocks which are
+/// not yet inserted into a backend.
+pub(crate) fn tree_route_by<B: Block, E, F>(
+	from_id: &B::Identifier,
+	to_id: &B::Identifier,
+	max_steps: Option<usize>,
+	mut fetch: F,
+) -> Result<Option<TreeRoute<B>>, E> where
+	F: FnMut(&B::Identifier) -> Result<(B, usize), E>,
+{
+	let mut steps = 0;
+	let mut step = || {
+		steps += 1;
+		max_steps.map(|max_steps| steps <= max_steps).unwrap_or(true)
+	};
+
+	let (mut from, mut from_depth) = fetch(from_id)?;
+	let (mut to, mut to_depth) = fetch(to_id)?;
 
 	let mut from_branch = Vec::new();
 	let mut to_branch = Vec::new();
 
-	{
-		let mut from_depth = backend.depth_at(from_id)?;
-		let mut to_depth = backend.depth_at(to_id)?;
-
-		while to_depth > from_depth {
-			let to_parent_id = match to.parent_id() {
-				Some(parent_id) => parent_id,
-				None => {
-					assert!(to_depth == 0, "When parent_id is None, depth should be 0");
-					break;
-				}
-			};
-
-			to_branch.push(to.id());
-			to = backend.block_at(&to_parent_id)?;
-			to_depth = backend.depth_at(&to_parent_id)?;
+	while to_depth > from_depth {
+		let to_parent_id = match to.parent_id() {
+			Some(parent_id) => parent_id,
+			None => {
+				assert!(to_depth == 0, "When parent_id is None, depth should be 0");
+				break;
+			}
+		};
+
+		if !step() {
+			return Ok(None)
 		}
 
-		while from_depth > to_depth {
-			let from_parent_id = match from.parent_id() {
-				Some(parent_id) => parent_id,
-				None => {
-					assert!(to_depth == 0, "When parent_id is None, depth should be 0");
-					break;
-				}
-			};
-
-			from_branch.push(from.id());
-			from = backend.block_at(&from_parent_id)?;
-			from_depth = backend.depth_at(&from_parent_id)?;
+		to_branch.push(to.id());
+		let (parent, parent_depth) = fetch(&to_parent_id)?;
+		to = parent;
+		to_depth = parent_depth;
+	}
+
+	while from_depth > to_depth {
+		let from_parent_id = match from.parent_id() {
+			Some(parent_id) => parent_id,
+			None => {
+				assert!(to_depth == 0, "When parent_id is None, depth should be 0");
+				break;
+			}
+		};
+
+		if !step() {
+			return Ok(None)
 		}
+
+		from_branch.push(from.id());
+		let (parent, parent_depth) = fetch(&from_parent_id)?;
+		from = parent;
+		from_depth = parent_depth;
 	}
 
 	while from.id() != to.id() {
@@ -127,11 +198,15 @@ pub fn tree_route<Ba: ChainQuery>(
 			}
 		};
 
+		if !(step() && step()) {
+			return Ok(None)
+		}
+
 		to_branch.push(to.id());
-		to = backend.block_at(&to_parent_id)?;
+		to = fetch(&to_parent_id)?.0;
 
 		from_branch.push(from.id());
-		from = backend.block_at(&from_parent_id)?;
+		from = fetch(&from_parent_id)?.0;
 	}
 
 	// add the pivot block. and append the reversed to-branch (note that it's reverse order originalls)
@@ -139,8 +214,102 @@ pub fn tree_route<Ba: ChainQuery>(
 	from_branch.push(to.id());
 	from_branch.extend(to_branch.into_iter().rev());
 
-	Ok(TreeRoute {
+	Ok(Some(TreeRoute {
 		route: from_branch,
 		pivot,
-	})
+	}))
+}
+
+/// Ancestry of a block, truncated to a maximum length.
+pub struct Ancestry<B: Block> {
+	ancestors: Vec<B::Identifier>,
+	has_more: bool,
+}
+
+impl<B: Block> Ancestry<B> {
+	/// Get a slice of ancestors, starting from the parent block, towards
+	/// genesis.
+	pub fn ancestors(&self) -> &[B::Identifier] {
+		&self.ancestors
+	}
+
+	/// Whether more ancestors exist beyond the truncated list.
+	pub fn has_more(&self) -> bool {
+		self.has_more
+	}
+}
+
+/// Enumerate ancestors of a block, returning at most `max_ancestors` of
+/// them. This bounds the work done when serving ancestry to peers.
+pub fn ancestors_bounded<Ba: ChainQuery>(
+	backend: &Ba,
+	id: &<Ba::Block as Block>::Identifier,
+	max_ancestors: usize,
+) -> Result<Ancestry<Ba::Block>, Ba::Error> {
+	let mut ancestors = Vec::new();
+	let mut current = backend.block_at(id)?;
+
+	while let Some(parent_id) = current.parent_id() {
+		if ancestors.len() >= max_ancestors {
+			return Ok(Ancestry { ancestors, has_more: true })
+		}
+
+		current = backend.block_at(&parent_id)?;
+		ancestors.push(parent_id);
+	}
+
+	Ok(Ancestry { ancestors, has_more: false })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::MemoryError;
+	use crate::test_support::{DummyBlock, import_chain, test_backend};
+
+	#[test]
+	fn bounded_route_fails_on_deep_chain() {
+		let mut backend = test_backend();
+		let blocks = (1..=1000).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &blocks, false).unwrap();
+
+		match tree_route_bounded(&backend, &0, &1000, 100) {
+			Err(MemoryError::RouteTooLong) => (),
+			other => panic!("Expected route too long, got {:?}", other.map(|route| route.enacted().len())),
+		}
+
+		let route = tree_route_bounded(&backend, &0, &1000, 1000).unwrap();
+		assert_eq!(route.enacted().len(), 1000);
+		assert_eq!(route.enacted(), tree_route(&backend, &0, &1000).unwrap().enacted());
+	}
+
+	#[test]
+	fn ancestors_are_truncated_to_cap() {
+		let mut backend = test_backend();
+		let blocks = (1..=10).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &blocks, false).unwrap();
+
+		let ancestry = ancestors_bounded(&backend, &10, 3).unwrap();
+		assert_eq!(ancestry.ancestors(), &[9, 8, 7]);
+		assert!(ancestry.has_more());
+
+		let ancestry = ancestors_bounded(&backend, &10, 10).unwrap();
+		assert_eq!(ancestry.ancestors().len(), 10);
+		assert!(!ancestry.has_more());
+	}
+
+	#[test]
+	fn route_is_rebuilt_from_parts() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+		import_chain(&mut backend, &[DummyBlock::new(3, 1), DummyBlock::new(4, 3)], false).unwrap();
+
+		let (route, pivot) = tree_route(&backend, &2, &4).unwrap().into_parts();
+		let rebuilt = TreeRoute::<DummyBlock>::from_parts(route.clone(), pivot).unwrap();
+		assert_eq!(rebuilt.retracted(), &[2]);
+		assert_eq!(rebuilt.common_block(), &1);
+		assert_eq!(rebuilt.enacted(), &[3, 4]);
+
+		assert!(TreeRoute::<DummyBlock>::from_parts(route, 4).is_none());
+	}
 }
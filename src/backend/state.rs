@@ -1,31 +1,121 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, BTreeMap};
 use std::error as stderror;
 use core::convert::Infallible;
-use crate::StorageExternalities;
+use crate::{StorageExternalities, AsExternalities};
 
-/// State stored in memory.
+/// Map of storage keys to values, backing a `GenericState`.
+pub trait StateMap: Clone + Default {
+	/// Get the value of a key.
+	fn get(&self, key: &[u8]) -> Option<&Vec<u8>>;
+	/// Insert a key-value pair.
+	fn insert(&mut self, key: Vec<u8>, value: Vec<u8>);
+	/// Remove a key.
+	fn remove(&mut self, key: &[u8]);
+	/// Iterate over all key-value pairs whose key starts with the prefix.
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_>;
+}
+
+impl StateMap for HashMap<Vec<u8>, Vec<u8>> {
+	fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+		HashMap::get(self, key)
+	}
+
+	fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		HashMap::insert(self, key, value);
+	}
+
+	fn remove(&mut self, key: &[u8]) {
+		HashMap::remove(self, key);
+	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		let prefix = prefix.to_vec();
+		Box::new(self.iter()
+			.filter(move |(key, _)| key.starts_with(&prefix))
+			.map(|(key, value)| (key.clone(), value.clone())))
+	}
+}
+
+impl StateMap for BTreeMap<Vec<u8>, Vec<u8>> {
+	fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+		BTreeMap::get(self, key)
+	}
+
+	fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		BTreeMap::insert(self, key, value);
+	}
+
+	fn remove(&mut self, key: &[u8]) {
+		BTreeMap::remove(self, key);
+	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		let prefix = prefix.to_vec();
+		Box::new(self.range(prefix.clone()..)
+			.take_while(move |(key, _)| key.starts_with(&prefix))
+			.map(|(key, value)| (key.clone(), value.clone())))
+	}
+}
+
+/// State stored in memory, backed by a `StateMap`.
 #[derive(Clone, Default)]
-pub struct KeyValueMemoryState {
-	storage: HashMap<Vec<u8>, Vec<u8>>,
+pub struct GenericState<M> {
+	storage: M,
+}
+
+/// State stored in memory, backed by a `HashMap`.
+pub type KeyValueMemoryState = GenericState<HashMap<Vec<u8>, Vec<u8>>>;
+
+/// State stored in memory, backed by a `BTreeMap`. Prefix iteration only
+/// visits matching keys, in order.
+pub type OrderedMemoryState = GenericState<BTreeMap<Vec<u8>, Vec<u8>>>;
+
+impl<M: StateMap> GenericState<M> {
+	fn read_storage_batch_impl(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+		keys.iter().map(|key| self.storage.get(key).cloned()).collect()
+	}
 }
 
-impl AsRef<HashMap<Vec<u8>, Vec<u8>>> for KeyValueMemoryState {
-	fn as_ref(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
+impl<M> AsRef<M> for GenericState<M> {
+	fn as_ref(&self) -> &M {
 		&self.storage
 	}
 }
 
-impl AsMut<HashMap<Vec<u8>, Vec<u8>>> for KeyValueMemoryState {
-	fn as_mut(&mut self) -> &mut HashMap<Vec<u8>, Vec<u8>> {
+impl<M> AsMut<M> for GenericState<M> {
+	fn as_mut(&mut self) -> &mut M {
 		&mut self.storage
 	}
 }
 
-impl StorageExternalities<Infallible> for KeyValueMemoryState {
+impl<M: StateMap> AsExternalities<GenericState<M>> for GenericState<M> {
+	fn as_externalities(&mut self) -> &mut GenericState<M> {
+		self
+	}
+}
+
+impl<M: StateMap + 'static> AsExternalities<dyn StorageExternalities<Infallible>> for GenericState<M> {
+	fn as_externalities(&mut self) -> &mut (dyn StorageExternalities<Infallible> + 'static) {
+		self
+	}
+}
+
+impl<M: StateMap + 'static> AsExternalities<dyn StorageExternalities<Box<dyn stderror::Error>>> for GenericState<M> {
+	fn as_externalities(&mut self) -> &mut (dyn StorageExternalities<Box<dyn stderror::Error>> + 'static) {
+		self
+	}
+}
+
+impl<M: StateMap> StorageExternalities<Infallible> for GenericState<M> {
 	fn read_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Infallible> {
 		Ok(self.storage.get(key).map(|value| value.to_vec()))
 	}
 
+	fn read_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Infallible> {
+		Ok(self.read_storage_batch_impl(keys))
+	}
+
 	fn write_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
 		self.storage.insert(key, value);
 	}
@@ -33,13 +123,21 @@ impl StorageExternalities<Infallible> for KeyValueMemoryState {
 	fn remove_storage(&mut self, key: &[u8]) {
 		self.storage.remove(key);
 	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		self.storage.iter_prefix(prefix)
+	}
 }
 
-impl StorageExternalities<Box<dyn stderror::Error>> for KeyValueMemoryState {
+impl<M: StateMap> StorageExternalities<Box<dyn stderror::Error>> for GenericState<M> {
 	fn read_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn stderror::Error>> {
 		Ok(self.storage.get(key).map(|value| value.to_vec()))
 	}
 
+	fn read_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Box<dyn stderror::Error>> {
+		Ok(self.read_storage_batch_impl(keys))
+	}
+
 	fn write_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
 		self.storage.insert(key, value);
 	}
@@ -47,4 +145,156 @@ impl StorageExternalities<Box<dyn stderror::Error>> for KeyValueMemoryState {
 	fn remove_storage(&mut self, key: &[u8]) {
 		self.storage.remove(key);
 	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		self.storage.iter_prefix(prefix)
+	}
+}
+
+/// State that borrows its parent and is only cloned on the first write.
+pub struct CowState<'a, S: Clone> {
+	state: Cow<'a, S>,
+}
+
+impl<'a, S: Clone> CowState<'a, S> {
+	/// Create a new copy-on-write state borrowing the parent state.
+	pub fn new(parent: &'a S) -> Self {
+		Self { state: Cow::Borrowed(parent) }
+	}
+
+	/// Whether the parent state has been cloned.
+	pub fn is_owned(&self) -> bool {
+		match self.state {
+			Cow::Borrowed(_) => false,
+			Cow::Owned(_) => true,
+		}
+	}
+
+	/// Get the resulting state, cloning the parent if it was never
+	/// written to.
+	pub fn into_owned(self) -> S {
+		self.state.into_owned()
+	}
+}
+
+impl<'a, S: StorageExternalities<Error> + Clone, Error> StorageExternalities<Error> for CowState<'a, S> {
+	fn read_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		self.state.read_storage(key)
+	}
+
+	fn read_storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+		self.state.read_storage_batch(keys)
+	}
+
+	fn write_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		self.state.to_mut().write_storage(key, value)
+	}
+
+	fn remove_storage(&mut self, key: &[u8]) {
+		self.state.to_mut().remove_storage(key)
+	}
+
+	fn iter_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + '_> {
+		self.state.iter_prefix(prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BlockExecutor;
+	use crate::backend::{ChainQuery, Committable, Operation};
+	use crate::test_support::{DummyBlock, CounterExecutor, counter, import_chain, test_backend};
+
+	#[test]
+	fn prefix_iteration_only_yields_matching_keys() {
+		let mut state = KeyValueMemoryState::default();
+		let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		for key in &[b"a1", b"a2", b"b1"] {
+			externalities.write_storage(key.to_vec(), key.to_vec());
+		}
+
+		let mut pairs = externalities.iter_prefix(b"a").collect::<Vec<_>>();
+		pairs.sort();
+		assert_eq!(pairs, vec![
+			(b"a1".to_vec(), b"a1".to_vec()),
+			(b"a2".to_vec(), b"a2".to_vec()),
+		]);
+	}
+
+	fn execute_on<S>(mut state: S) where
+		S: AsExternalities<dyn StorageExternalities<Infallible>>,
+	{
+		CounterExecutor::default()
+			.execute_block(&DummyBlock::genesis(), state.as_externalities())
+			.expect("Counter execution never fails; qed");
+	}
+
+	#[test]
+	fn borrowed_state_is_used_as_externalities() {
+		let mut state = KeyValueMemoryState::default();
+		execute_on(&mut state);
+		execute_on(&mut state);
+		assert_eq!(counter(&mut state), 2);
+	}
+
+	#[test]
+	fn fork_executed_on_borrowed_state_keeps_parent_intact() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+
+		let state = backend.with_state_at(&1, |parent| {
+			let mut state = CowState::new(parent);
+			assert_eq!(StorageExternalities::<Infallible>::read_storage(&state, b"counter"), Ok(None));
+			assert!(!state.is_owned());
+
+			let externalities: &mut dyn StorageExternalities<Infallible> = &mut state;
+			externalities.write_storage(b"counter".to_vec(), 5u64.to_le_bytes().to_vec());
+			assert!(state.is_owned());
+			state.into_owned()
+		}).unwrap();
+		backend.commit(Operation::builder().import(DummyBlock::new(3, 1), state).build()).unwrap();
+
+		assert_eq!(backend.head(), 2);
+		assert_eq!(counter(&mut backend.state_at(&1).unwrap()), 0);
+		assert_eq!(counter(&mut backend.state_at(&3).unwrap()), 5);
+	}
+
+	#[test]
+	fn batch_read_matches_single_reads() {
+		let mut state = KeyValueMemoryState::default();
+		let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		externalities.write_storage(b"a".to_vec(), vec![1]);
+		externalities.write_storage(b"b".to_vec(), vec![2]);
+
+		let keys: &[&[u8]] = &[b"b", b"missing", b"a"];
+		let single = keys.iter()
+			.map(|key| externalities.read_storage(key))
+			.collect::<Result<Vec<_>, _>>();
+		assert_eq!(externalities.read_storage_batch(keys), single);
+		assert_eq!(single, Ok(vec![Some(vec![2]), None, Some(vec![1])]));
+	}
+
+	fn exercise_backing<M: StateMap + 'static>() -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut state = GenericState::<M>::default();
+		let externalities: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		for key in &[b"a2", b"b1", b"a1", b"a3"] {
+			externalities.write_storage(key.to_vec(), key.to_vec());
+		}
+		externalities.remove_storage(b"a3");
+		assert_eq!(externalities.read_storage(b"a3"), Ok(None));
+		assert_eq!(externalities.read_storage(b"b1"), Ok(Some(b"b1".to_vec())));
+
+		externalities.iter_prefix(b"a").collect()
+	}
+
+	#[test]
+	fn both_backings_behave_alike() {
+		let expected = vec![(b"a1".to_vec(), b"a1".to_vec()), (b"a2".to_vec(), b"a2".to_vec())];
+
+		let mut unordered = exercise_backing::<HashMap<Vec<u8>, Vec<u8>>>();
+		unordered.sort();
+		assert_eq!(unordered, expected);
+		assert_eq!(exercise_backing::<BTreeMap<Vec<u8>, Vec<u8>>>(), expected);
+	}
 }
@@ -1,5 +1,7 @@
 use std::error as stderror;
-use crate::{Block, Auxiliary};
+use sha3::{Digest, Sha3_256};
+use crate::{Block, HasHeader, Auxiliary};
+use crate::backend::{tree_route, TreeRoute};
 
 /// Backend store definition for a block context.
 pub trait Store {
@@ -19,8 +21,19 @@ pub trait OperationError: stderror::Error {
 	fn invalid_operation() -> Self;
 	/// Trying to import a block that is genesis
 	fn block_is_genesis() -> Self;
+	/// Tree route exceeded the maximum allowed steps
+	fn route_too_long() -> Self where Self: Sized {
+		Self::invalid_operation()
+	}
 }
 
+/// Key of the auxiliary values of a store.
+pub type AuxiliaryKey<S> = <<S as Store>::Auxiliary as Auxiliary<<S as Store>::Block>>::Key;
+/// Auxiliary value of a store, together with its key.
+pub type AuxiliaryEntry<S> = (AuxiliaryKey<S>, <S as Store>::Auxiliary);
+/// Blocks along a tree route, as (retracted, enacted).
+pub type BlockPath<B> = (Vec<B>, Vec<B>);
+
 /// Chain query interface for a backend.
 pub trait ChainQuery: Store {
 	/// Get the genesis hash of the chain.
@@ -52,6 +65,13 @@ pub trait ChainQuery: Store {
 		key: &<Self::Auxiliary as Auxiliary<Self::Block>>::Key,
 	) -> Result<Option<Self::Auxiliary>, Self::Error>;
 
+	/// Get all auxiliary values, together with their keys, in no particular
+	/// order. Every value is cloned, so this is meant for enumeration
+	/// rather than lookups.
+	fn auxiliaries(
+		&self,
+	) -> Result<Vec<AuxiliaryEntry<Self>>, Self::Error>;
+
 	/// Get the depth of a block.
 	fn depth_at(
 		&self,
@@ -64,6 +84,17 @@ pub trait ChainQuery: Store {
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error>;
 
+	/// Get a page of children of a block, skipping `offset` children and
+	/// returning at most `limit` of them.
+	fn children_paginated(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		offset: usize,
+		limit: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		Ok(self.children_at(hash)?.into_iter().skip(offset).take(limit).collect())
+	}
+
 	/// Get the state object of a block.
 	fn state_at(
 		&self,
@@ -75,6 +106,230 @@ pub trait ChainQuery: Store {
 		&self,
 		hash: &<Self::Block as Block>::Identifier,
 	) -> Result<Self::Block, Self::Error>;
+
+	/// Get the state root of a block, if it is stored. Backends that do
+	/// not store state roots always return none.
+	fn state_root_at(
+		&self,
+		_hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		Ok(None)
+	}
+
+	/// Check whether a block is known and canonical. Unlike `is_canon`,
+	/// returns false instead of an error for unknown blocks.
+	fn contains_canon(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<bool, Self::Error> {
+		if !self.contains(hash)? {
+			return Ok(false)
+		}
+
+		self.is_canon(hash)
+	}
+
+	/// Call `f` with the state object of a block. Backends that store
+	/// states in place lend it without cloning.
+	fn with_state_at<R, F: FnOnce(&Self::State) -> R>(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+		f: F,
+	) -> Result<R, Self::Error> {
+		Ok(f(&self.state_at(hash)?))
+	}
+
+	/// Get the depth of a block, or `None` for unknown blocks.
+	fn try_depth_at(
+		&self,
+		hash: &<Self::Block as Block>::Identifier,
+	) -> Result<Option<usize>, Self::Error> {
+		if !self.contains(hash)? {
+			return Ok(None)
+		}
+
+		self.depth_at(hash).map(Some)
+	}
+
+	/// Get the state object of the genesis block.
+	fn genesis_state(&self) -> Result<Self::State, Self::Error> {
+		self.state_at(&self.genesis())
+	}
+
+	/// Walk ancestors of a block, starting from its parent towards genesis,
+	/// calling `f` on each of them. The walk stops when `f` returns false.
+	fn walk_ancestors<F: FnMut(&Self::Block) -> bool>(
+		&self,
+		start: &<Self::Block as Block>::Identifier,
+		mut f: F,
+	) -> Result<(), Self::Error> {
+		let mut current = self.block_at(start)?;
+
+		while let Some(parent_id) = current.parent_id() {
+			current = self.block_at(&parent_id)?;
+			if !f(&current) {
+				break
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Get all blocks at a given depth, including non-canonical ones.
+	fn blocks_at_depth(
+		&self,
+		depth: usize,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		let mut blocks = vec![self.genesis()];
+
+		for _ in 0..depth {
+			let mut children = Vec::new();
+			for id in blocks {
+				children.append(&mut self.children_at(&id)?);
+			}
+			blocks = children;
+		}
+
+		Ok(blocks)
+	}
+
+	/// Get all fork points, blocks that have more than one child.
+	fn fork_points(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		let mut fork_points = Vec::new();
+		let mut pending = vec![self.genesis()];
+
+		while let Some(id) = pending.pop() {
+			let children = self.children_at(&id)?;
+			if children.len() > 1 {
+				fork_points.push(id);
+			}
+			pending.extend(children);
+		}
+
+		Ok(fork_points)
+	}
+
+	/// Get all known blocks whose id starts with the given bytes.
+	fn find_by_prefix(
+		&self,
+		prefix: &[u8],
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> where
+		<Self::Block as Block>::Identifier: AsRef<[u8]>,
+	{
+		let mut found = Vec::new();
+		let mut pending = vec![self.genesis()];
+
+		while let Some(id) = pending.pop() {
+			pending.extend(self.children_at(&id)?);
+			if id.as_ref().starts_with(prefix) {
+				found.push(id);
+			}
+		}
+
+		Ok(found)
+	}
+
+	/// Get all leaves, blocks that do not have any children.
+	fn leaves(
+		&self,
+	) -> Result<Vec<<Self::Block as Block>::Identifier>, Self::Error> {
+		let mut leaves = Vec::new();
+		let mut pending = vec![self.genesis()];
+
+		while let Some(id) = pending.pop() {
+			let children = self.children_at(&id)?;
+			if children.is_empty() {
+				leaves.push(id);
+			}
+			pending.extend(children);
+		}
+
+		Ok(leaves)
+	}
+
+	/// Get a checksum of the canonical chain, folding all canonical block
+	/// ids from genesis to head through a rolling hash. Two identical
+	/// canonical chains produce the same checksum. Fails with an invalid
+	/// operation error if the canonical chain changes while it is folded.
+	fn canon_checksum(&self) -> Result<[u8; 32], Self::Error> where
+		<Self::Block as Block>::Identifier: AsRef<[u8]>,
+		Self::Error: OperationError,
+	{
+		let head_depth = self.depth_at(&self.head())?;
+		let mut checksum = [0u8; 32];
+
+		for depth in 0..=head_depth {
+			let id = self.lookup_canon_depth(depth)?
+				.ok_or_else(Self::Error::invalid_operation)?;
+
+			let mut hasher = Sha3_256::new();
+			hasher.input(checksum);
+			hasher.input(id.as_ref());
+			checksum.copy_from_slice(hasher.result().as_slice());
+		}
+
+		Ok(checksum)
+	}
+
+	/// Get the blocks along the tree route between two blocks, as
+	/// (retracted, enacted). Retracted blocks are ordered towards the
+	/// common ancestor, and enacted blocks away from it.
+	fn path_between(
+		&self,
+		from: &<Self::Block as Block>::Identifier,
+		to: &<Self::Block as Block>::Identifier,
+	) -> Result<BlockPath<Self::Block>, Self::Error> where
+		Self: Sized,
+	{
+		let route = tree_route(self, from, to)?;
+
+		let retracted = route.retracted().iter()
+			.map(|id| self.block_at(id))
+			.collect::<Result<Vec<_>, _>>()?;
+		let enacted = route.enacted().iter()
+			.map(|id| self.block_at(id))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok((retracted, enacted))
+	}
+
+	/// Get the headers linking `from` to its descendant `to`, ordered from
+	/// `from` to `to`, both inclusive. Each header's parent id is the id of
+	/// the previous one. Fails if `from` is not an ancestor of `to`.
+	fn ancestry_proof(
+		&self,
+		from: &<Self::Block as Block>::Identifier,
+		to: &<Self::Block as Block>::Identifier,
+	) -> Result<Vec<<Self::Block as HasHeader>::Header>, Self::Error> where
+		Self::Block: HasHeader,
+		Self::Error: OperationError,
+	{
+		let from_depth = self.depth_at(from)?;
+		let to_depth = self.depth_at(to)?;
+		if from_depth > to_depth {
+			return Err(Self::Error::invalid_operation())
+		}
+
+		let mut headers = Vec::with_capacity(to_depth - from_depth + 1);
+		let mut current = self.block_at(to)?;
+		for _ in from_depth..to_depth {
+			let parent_id = current.parent_id()
+				.expect("Block is deeper than another block; \
+					it is not genesis; qed");
+			headers.push(current.header());
+			current = self.block_at(&parent_id)?;
+		}
+
+		if current.id() != *from {
+			return Err(Self::Error::invalid_operation())
+		}
+		headers.push(current.header());
+		headers.reverse();
+
+		Ok(headers)
+	}
 }
 
 /// Database settlement for chain backend.
@@ -95,6 +350,13 @@ pub trait ChainSettlement: Store {
 		id: <Self::Block as Block>::Identifier,
 		child: <Self::Block as Block>::Identifier,
 	);
+	/// Set state root of a block. Backends that do not store state roots
+	/// ignore it.
+	fn set_state_root(
+		&mut self,
+		_id: <Self::Block as Block>::Identifier,
+		_state_root: Vec<u8>,
+	) { }
 	/// Set canon.
 	fn set_canon(
 		&mut self,
@@ -140,6 +402,25 @@ pub trait Committable: Store {
 		&mut self,
 		operation: Self::Operation,
 	) -> Result<(), Self::Error>;
+
+	/// Commit operation, returning the tree route from the old head to the
+	/// new head, if the head has changed. By default the route is
+	/// recomputed from the heads before and after the commit.
+	fn commit_with_route(
+		&mut self,
+		operation: Self::Operation,
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> where
+		Self: ChainQuery + Sized,
+	{
+		let old_head = self.head();
+		self.commit(operation)?;
+		let new_head = self.head();
+
+		if old_head == new_head {
+			return Ok(None)
+		}
+		tree_route(self, &old_head, &new_head).map(Some)
+	}
 }
 
 /// Shared committable backend.
@@ -152,4 +433,246 @@ pub trait SharedCommittable: Store + Clone {
 		&self,
 		operation: Self::Operation,
 	) -> Result<(), Self::Error>;
+
+	/// Commit operation, returning the tree route from the old head to the
+	/// new head, if the head has changed. By default the route is
+	/// recomputed from the heads before and after the commit.
+	fn commit_with_route(
+		&self,
+		operation: Self::Operation,
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> where
+		Self: ChainQuery,
+	{
+		let old_head = self.head();
+		self.commit(operation)?;
+		let new_head = self.head();
+
+		if old_head == new_head {
+			return Ok(None)
+		}
+		tree_route(self, &old_head, &new_head).map(Some)
+	}
+
+	/// Commit operation only if the current head still equals
+	/// `expected_head`. Returns false without committing on mismatch, so
+	/// that the caller can rebuild the operation and retry.
+	///
+	/// The default checks the head before committing, which is only atomic
+	/// if all commits are serialized by the import lock. Backends should
+	/// override it to check under their own write lock.
+	fn commit_if_head(
+		&self,
+		expected_head: &<Self::Block as Block>::Identifier,
+		operation: Self::Operation,
+	) -> Result<bool, Self::Error> where
+		Self: ChainQuery,
+	{
+		if self.head() != *expected_head {
+			return Ok(false)
+		}
+
+		self.commit(operation)?;
+		Ok(true)
+	}
+
+	/// Make all committed operations durable. Backends without persistence
+	/// have nothing to do.
+	fn flush(&self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::{MemoryBackend, KeyValueMemoryState, Operation, ImportOperation};
+	use crate::{BlockExecutor, AsExternalities};
+	use crate::test_support::{DummyBlock, CounterExecutor, counter, import_chain, test_backend};
+
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	struct ByteBlock {
+		id: [u8; 8],
+		parent: Option<[u8; 8]>,
+	}
+
+	impl Block for ByteBlock {
+		type Identifier = [u8; 8];
+
+		fn id(&self) -> [u8; 8] { self.id }
+		fn parent_id(&self) -> Option<[u8; 8]> { self.parent }
+	}
+
+	fn byte_block(id: u64, parent: u64) -> ByteBlock {
+		ByteBlock { id: id.to_le_bytes(), parent: Some(parent.to_le_bytes()) }
+	}
+
+	fn import<B: Block>(backend: &mut MemoryBackend<B, (), KeyValueMemoryState>, block: B) {
+		let id = block.id();
+		backend.commit(Operation {
+			import_block: vec![ImportOperation { block, state: Default::default() }],
+			set_head: Some(id),
+			..Default::default()
+		}).unwrap();
+	}
+
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	struct TestHeader {
+		id: u64,
+		parent: Option<u64>,
+	}
+
+	impl Block for TestHeader {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.id }
+		fn parent_id(&self) -> Option<u64> { self.parent }
+	}
+
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	struct BodyBlock {
+		header: TestHeader,
+		body: Vec<u8>,
+	}
+
+	impl Block for BodyBlock {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.header.id }
+		fn parent_id(&self) -> Option<u64> { self.header.parent }
+	}
+
+	impl HasHeader for BodyBlock {
+		type Header = TestHeader;
+
+		fn header(&self) -> TestHeader { self.header.clone() }
+	}
+
+	fn body_block(id: u64, parent: Option<u64>) -> BodyBlock {
+		BodyBlock { header: TestHeader { id, parent }, body: vec![0; 32] }
+	}
+
+	#[test]
+	fn canon_checksums_match_for_identical_chains() {
+		let genesis = ByteBlock { id: 0u64.to_le_bytes(), parent: None };
+		let mut first = MemoryBackend::new_with_genesis(genesis.clone(), Default::default());
+		let mut second = MemoryBackend::new_with_genesis(genesis, Default::default());
+		for id in 1..=10 {
+			import(&mut first, byte_block(id, id - 1));
+			import(&mut second, byte_block(id, id - 1));
+		}
+		assert_eq!(first.canon_checksum().unwrap(), second.canon_checksum().unwrap());
+
+		import(&mut second, byte_block(11, 10));
+		assert_ne!(first.canon_checksum().unwrap(), second.canon_checksum().unwrap());
+	}
+
+	#[test]
+	fn ancestors_are_walked_until_stopped() {
+		let mut backend = test_backend();
+		let blocks = (1..=10).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &blocks, true).unwrap();
+
+		let mut ancestors = 0;
+		backend.walk_ancestors(&10, |_| {
+			ancestors += 1;
+			true
+		}).unwrap();
+		assert_eq!(ancestors, 10);
+
+		let mut walked = Vec::new();
+		backend.walk_ancestors(&10, |block| {
+			walked.push(block.id);
+			walked.len() < 5
+		}).unwrap();
+		assert_eq!(walked, vec![9, 8, 7, 6, 5]);
+	}
+
+	#[test]
+	fn genesis_state_is_state_at_genesis() {
+		let mut genesis_state = KeyValueMemoryState::default();
+		CounterExecutor::default()
+			.execute_block(&DummyBlock::genesis(), genesis_state.as_externalities())
+			.unwrap();
+		let mut backend = MemoryBackend::new_with_genesis(DummyBlock::genesis(), genesis_state);
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+
+		assert_eq!(counter(&mut backend.genesis_state().unwrap()), 1);
+		assert_eq!(counter(&mut backend.state_at(&backend.genesis()).unwrap()), 1);
+		assert_eq!(counter(&mut backend.state_at(&2).unwrap()), 0);
+	}
+
+	#[test]
+	fn only_known_canon_blocks_are_contained_in_canon() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+		import_chain(&mut backend, &[DummyBlock::new(3, 1)], false).unwrap();
+
+		assert!(!backend.contains_canon(&4).unwrap());
+		assert!(!backend.contains_canon(&3).unwrap());
+		assert!(backend.contains_canon(&2).unwrap());
+		assert!(backend.contains_canon(&0).unwrap());
+	}
+
+	#[test]
+	fn path_between_returns_reorg_blocks() {
+		let mut backend = test_backend();
+		let old = (1..=3).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+		import_chain(&mut backend, &old, true).unwrap();
+		let new = vec![DummyBlock::new(10, 1), DummyBlock::new(11, 10), DummyBlock::new(12, 11)];
+		import_chain(&mut backend, &new, true).unwrap();
+
+		let (retracted, enacted) = backend.path_between(&3, &12).unwrap();
+		assert_eq!(retracted, vec![old[2].clone(), old[1].clone()]);
+		assert_eq!(enacted, new);
+	}
+
+	#[test]
+	fn ancestry_proof_links_headers() {
+		let mut backend = MemoryBackend::new_with_genesis(body_block(0, None), Default::default());
+		for id in 1..=10 {
+			import(&mut backend, body_block(id, Some(id - 1)));
+		}
+		import(&mut backend, body_block(11, Some(2)));
+
+		let proof = backend.ancestry_proof(&3, &10).unwrap();
+		assert_eq!(proof.first().map(|header| header.id()), Some(3));
+		assert_eq!(proof.last().map(|header| header.id()), Some(10));
+		for pair in proof.windows(2) {
+			assert_eq!(pair[1].parent_id(), Some(pair[0].id()));
+		}
+		assert_eq!(proof.len(), 8);
+
+		assert!(backend.ancestry_proof(&11, &10).is_err());
+		assert!(backend.ancestry_proof(&10, &3).is_err());
+	}
+
+	#[test]
+	fn depth_of_unknown_block_is_none() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], false).unwrap();
+
+		assert_eq!(backend.try_depth_at(&2).unwrap(), Some(2));
+		assert_eq!(backend.try_depth_at(&0).unwrap(), Some(0));
+		assert_eq!(backend.try_depth_at(&3).unwrap(), None);
+		assert!(backend.depth_at(&3).is_err());
+	}
+
+	#[test]
+	fn blocks_are_found_by_id_prefix() {
+		let genesis = ByteBlock { id: 0u64.to_le_bytes(), parent: None };
+		let mut backend = MemoryBackend::new_with_genesis(genesis, Default::default());
+		let (first, second, other) = (0x0403_0201, 0x05_0403_0201, 0x0605);
+		import(&mut backend, byte_block(first, 0));
+		import(&mut backend, byte_block(second, first));
+		import(&mut backend, byte_block(other, 0));
+
+		let first_id = first.to_le_bytes();
+		assert_eq!(backend.find_by_prefix(&first_id).unwrap(), vec![first_id]);
+
+		let mut found = backend.find_by_prefix(&first_id[..4]).unwrap();
+		found.sort();
+		assert_eq!(found, vec![first_id, second.to_le_bytes()]);
+
+		assert!(backend.find_by_prefix(&[0xff]).unwrap().is_empty());
+	}
 }
@@ -1,6 +1,7 @@
 use std::sync::MutexGuard;
-use crate::backend::{SharedCommittable, Store, Operation, ImportOperation};
-use crate::{Block, Auxiliary};
+use crate::backend::{SharedCommittable, Store, ChainQuery, TreeRoute, Operation, ImportOperation, LazyImportOperation, OperationError};
+use crate::import::{Error, check_spec_version};
+use crate::{Block, Auxiliary, BlockExecutor, VersionedBlock, AsExternalities};
 
 /// Block importer.
 pub struct ImportAction<'a, Ba: Store> {
@@ -39,16 +40,61 @@ impl<'a, Ba: Store> ImportAction<'a, Ba> where
 		self.import_raw(ImportOperation { block, state });
 	}
 
+	/// Import a new block, failing early instead of at commit time if its
+	/// parent is neither known to the backend nor pending in this action.
+	pub fn import_block_checked(
+		&mut self,
+		block: Ba::Block,
+		state: Ba::State,
+	) -> Result<(), Ba::Error> where
+		Ba: ChainQuery,
+		Ba::Error: OperationError,
+	{
+		match block.parent_id() {
+			Some(parent_id) => {
+				let pending = self.pending.import_block.iter().map(|op| op.block.id())
+					.chain(self.pending.import_lazy_block.iter().map(|op| op.block.id()))
+					.any(|id| id == parent_id);
+
+				if !pending && !self.backend.contains(&parent_id)? {
+					return Err(Ba::Error::invalid_operation())
+				}
+			},
+			None => if block.id() != self.backend.genesis() {
+				return Err(Ba::Error::block_is_genesis())
+			},
+		}
+
+		self.import_block(block, state);
+		Ok(())
+	}
+
 	/// Import a raw operation.
 	pub fn import_raw(&mut self, raw: ImportOperation<Ba::Block, Ba::State>) {
 		self.pending.import_block.push(raw);
 	}
 
+	/// Import a new block, whose state is computed from its parent state
+	/// at commit time.
+	pub fn import_lazy_block<F>(&mut self, block: Ba::Block, state: F) where
+		F: FnOnce(&Ba::State) -> Ba::State + Send + 'static,
+	{
+		self.pending.import_lazy_block.push(LazyImportOperation {
+			block,
+			state: Box::new(state),
+		});
+	}
+
 	/// Set head to given hash.
 	pub fn set_head(&mut self, head: <Ba::Block as Block>::Identifier) {
 		self.pending.set_head = Some(head);
 	}
 
+	/// Set state root of a block.
+	pub fn set_state_root(&mut self, id: <Ba::Block as Block>::Identifier, state_root: Vec<u8>) {
+		self.pending.insert_state_roots.push((id, state_root));
+	}
+
 	/// Insert auxiliary value.
 	pub fn insert_auxiliary(&mut self, aux: Ba::Auxiliary) {
 		self.pending.insert_auxiliaries.push(aux);
@@ -59,8 +105,187 @@ impl<'a, Ba: Store> ImportAction<'a, Ba> where
 		self.pending.remove_auxiliaries.push(aux_key);
 	}
 
+	/// Execute a block against the state of an arbitrary block, returning
+	/// the resulting state without committing anything. Operations the backend buffered
+	/// are flushed first, so that their blocks can be executed against.
+	pub fn execute_against<E>(
+		&self,
+		executor: &E,
+		block: &Ba::Block,
+		state_id: &<Ba::Block as Block>::Identifier,
+	) -> Result<Ba::State, Error> where
+		Ba: ChainQuery,
+		E: BlockExecutor<Block=Ba::Block>,
+		Ba::State: AsExternalities<E::Externalities>,
+	{
+		self.backend.flush()
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		let mut state = self.backend.state_at(state_id)
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		if let Some(parent_id) = block.parent_id() {
+			executor.set_parent_context(state.as_externalities(), &parent_id);
+		}
+		executor.execute_block(block, state.as_externalities())
+			.map_err(|e| Error::Executor(Box::new(e)))?;
+
+		Ok(state)
+	}
+
+	/// Execute a block against the state of an arbitrary block, like
+	/// `execute_against`, rejecting blocks not of the executor's spec version.
+	pub fn execute_versioned_against<E>(
+		&self,
+		executor: &E,
+		block: &Ba::Block,
+		state_id: &<Ba::Block as Block>::Identifier,
+	) -> Result<Ba::State, Error> where
+		Ba: ChainQuery,
+		Ba::Block: VersionedBlock,
+		E: BlockExecutor<Block=Ba::Block>,
+		Ba::State: AsExternalities<E::Externalities>,
+	{
+		check_spec_version(executor, block)?;
+		self.execute_against(executor, block, state_id)
+	}
+
 	/// Commit operation and drop import lock.
 	pub fn commit(self) -> Result<(), Ba::Error> {
 		Ok(self.backend.commit(self.into())?)
 	}
+
+	/// Commit operation and drop import lock, returning the tree route from
+	/// the old head to the new head, if the head has changed.
+	pub fn commit_with_route(self) -> Result<Option<TreeRoute<Ba::Block>>, Ba::Error> where
+		Ba: ChainQuery,
+	{
+		self.backend.commit_with_route(self.into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{BlockExecutor, AsExternalities};
+	use crate::backend::{SharedCommittable, ChainQuery, KeyValueMemoryState, MemoryError};
+	use crate::import::Error;
+	use crate::test_support::{
+		DummyBlock, CounterExecutor, counter, import_operation, import_shared_chain, shared_test_backend,
+	};
+
+	fn executed(block: &DummyBlock, parent_state: &KeyValueMemoryState) -> KeyValueMemoryState {
+		let mut state = parent_state.clone();
+		CounterExecutor::default().execute_block(block, state.as_externalities())
+			.expect("Counter execution never fails; qed");
+		state
+	}
+
+	#[test]
+	fn commit_with_route_returns_reorg() {
+		let backend = shared_test_backend();
+		import_shared_chain(&backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+
+		let mut action = backend.begin_action();
+		action.import_block(DummyBlock::new(3, 1), Default::default());
+		action.import_block(DummyBlock::new(4, 3), Default::default());
+		action.set_head(4);
+		let route = action.commit_with_route().unwrap().unwrap();
+		assert_eq!(route.common_block(), &1);
+		assert_eq!(route.retracted(), &[2]);
+		assert_eq!(route.enacted(), &[3, 4]);
+
+		let mut action = backend.begin_action();
+		action.import_block(DummyBlock::new(5, 0), Default::default());
+		assert!(action.commit_with_route().unwrap().is_none());
+		assert!(backend.commit_with_route(import_operation(&[DummyBlock::new(6, 5)])).unwrap().is_none());
+	}
+
+	#[test]
+	fn block_of_future_spec_version_is_rejected() {
+		let backend = shared_test_backend();
+		let action = backend.begin_action();
+		let executor = CounterExecutor::default();
+
+		let block = DummyBlock::new(1, 0).with_spec_version(1);
+		match action.execute_versioned_against(&executor, &block, &0) {
+			Err(Error::IncompatibleSpecVersion { expected: 0, found: 1 }) => (),
+			_ => panic!("Block of a future spec version must be rejected"),
+		}
+
+		let block = DummyBlock::new(1, 0);
+		assert!(action.execute_versioned_against(&executor, &block, &0).is_ok());
+	}
+
+	#[test]
+	fn lazy_import_matches_eager_import() {
+		let blocks = (1..=3).map(|id| DummyBlock::new(id, id - 1)).collect::<Vec<_>>();
+
+		let eager = shared_test_backend();
+		let mut action = eager.begin_action();
+		let mut state = eager.state_at(&0).unwrap();
+		for block in &blocks {
+			state = executed(block, &state);
+			action.import_block(block.clone(), state.clone());
+		}
+		action.commit().unwrap();
+
+		let lazy = shared_test_backend();
+		let mut action = lazy.begin_action();
+		for block in blocks.iter().rev() {
+			let executed_block = block.clone();
+			action.import_lazy_block(block.clone(), move |parent_state| {
+				executed(&executed_block, parent_state)
+			});
+		}
+		action.commit().unwrap();
+
+		for block in &blocks {
+			assert_eq!(
+				counter(&mut lazy.state_at(&block.id).unwrap()),
+				counter(&mut eager.state_at(&block.id).unwrap()),
+			);
+		}
+		assert_eq!(counter(&mut lazy.state_at(&3).unwrap()), 3);
+	}
+
+	#[test]
+	fn block_is_executed_against_mid_chain_state() {
+		let backend = shared_test_backend();
+		let mut action = backend.begin_action();
+		let mut state = backend.state_at(&0).unwrap();
+		for id in 1..=5 {
+			state = executed(&DummyBlock::new(id, id - 1), &state);
+			action.import_block(DummyBlock::new(id, id - 1), state.clone());
+		}
+		action.set_head(5);
+		action.commit().unwrap();
+
+		let action = backend.begin_action();
+		let executor = CounterExecutor::default();
+		let block = DummyBlock::new(10, 2);
+		assert_eq!(counter(&mut action.execute_against(&executor, &block, &2).unwrap()), 3);
+		assert_eq!(counter(&mut action.execute_against(&executor, &block, &4).unwrap()), 5);
+
+		assert!(!backend.contains(&10).unwrap());
+		assert_eq!(backend.head(), 5);
+		assert_eq!(counter(&mut backend.state_at(&2).unwrap()), 2);
+	}
+
+	#[test]
+	fn missing_parent_is_rejected_when_queued() {
+		let backend = shared_test_backend();
+		let mut action = backend.begin_action();
+
+		match action.import_block_checked(DummyBlock::new(2, 1), Default::default()) {
+			Err(MemoryError::InvalidOperation) => (),
+			other => panic!("Expected missing parent to be rejected, got {:?}", other),
+		}
+		match action.import_block_checked(DummyBlock { parent: None, ..DummyBlock::new(9, 0) }, Default::default()) {
+			Err(MemoryError::IsGenesis) => (),
+			other => panic!("Expected second genesis to be rejected, got {:?}", other),
+		}
+
+		action.import_block_checked(DummyBlock::new(1, 0), Default::default()).unwrap();
+		action.import_block_checked(DummyBlock::new(2, 1), Default::default()).unwrap();
+		action.commit().unwrap();
+		assert!(backend.contains(&2).unwrap());
+	}
 }
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use crate::Block;
+use crate::backend::{TreeRoute, OperationError};
+use crate::import::BlockImporter;
+
+/// Bounded least-recently-used cache of block validity, keyed by block id.
+///
+/// Importers consult it before executing a block: a block cached as valid
+/// may skip execution, and a block cached as invalid may be rejected
+/// immediately.
+pub struct ValidatedBlockCache<Id> {
+	capacity: usize,
+	validities: HashMap<Id, (bool, u64)>,
+	order: BTreeMap<u64, Id>,
+	next_use: u64,
+}
+
+impl<Id: Clone + Eq + Hash> ValidatedBlockCache<Id> {
+	/// Create a new cache holding at most `capacity` block ids.
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			capacity,
+			validities: HashMap::new(),
+			order: BTreeMap::new(),
+			next_use: 0,
+		}
+	}
+
+	/// Get the cached validity of a block, marking it as recently used.
+	pub fn validity(&mut self, id: &Id) -> Option<bool> {
+		let valid = self.validities.get(id)?.0;
+		self.insert(id.clone(), valid);
+		Some(valid)
+	}
+
+	/// Note that a block is valid.
+	pub fn note_valid(&mut self, id: Id) {
+		self.insert(id, true);
+	}
+
+	/// Note that a block is invalid.
+	pub fn note_invalid(&mut self, id: Id) {
+		self.insert(id, false);
+	}
+
+	fn insert(&mut self, id: Id, valid: bool) {
+		if self.capacity == 0 {
+			return
+		}
+
+		let last_use = self.next_use;
+		self.next_use += 1;
+		if let Some((_, previous_use)) = self.validities.insert(id.clone(), (valid, last_use)) {
+			self.order.remove(&previous_use);
+		}
+		self.order.insert(last_use, id);
+
+		while self.order.len() > self.capacity {
+			let least_used = *self.order.keys().next()
+				.expect("Order is longer than capacity, which is non-zero; qed");
+			if let Some(evicted) = self.order.remove(&least_used) {
+				self.validities.remove(&evicted);
+			}
+		}
+	}
+}
+
+/// An importer skipping blocks it has already imported, and rejecting
+/// blocks noted as invalid, using a `ValidatedBlockCache`.
+///
+/// Successfully imported blocks are cached as valid, so that the same
+/// block received from several peers is only executed once. Failed imports
+/// are not cached as invalid, as they may succeed later, for example once
+/// their parent is imported; use `note_invalid` for blocks known to be
+/// invalid.
+pub struct CachedImporter<I: BlockImporter> {
+	importer: I,
+	cache: ValidatedBlockCache<<I::Block as Block>::Identifier>,
+}
+
+impl<I: BlockImporter> CachedImporter<I> {
+	/// Create a new cached importer, caching at most `capacity` block ids.
+	pub fn new(importer: I, capacity: usize) -> Self {
+		Self {
+			importer,
+			cache: ValidatedBlockCache::new(capacity),
+		}
+	}
+
+	/// Note that a block is invalid, so that importing it is rejected
+	/// without executing it.
+	pub fn note_invalid(&mut self, id: <I::Block as Block>::Identifier) {
+		self.cache.note_invalid(id);
+	}
+}
+
+impl<I: BlockImporter> BlockImporter for CachedImporter<I> where
+	I::Error: OperationError,
+{
+	type Block = I::Block;
+	type Error = I::Error;
+
+	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
+		self.import_block_with_route(block)?;
+		Ok(())
+	}
+
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		let id = block.id();
+		match self.cache.validity(&id) {
+			Some(true) => return Ok(None),
+			Some(false) => return Err(I::Error::invalid_operation()),
+			None => (),
+		}
+
+		let route = self.importer.import_block_with_route(block)?;
+		self.cache.note_valid(id);
+		Ok(route)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::{ChainQuery, SharedCommittable, MemoryError};
+	use crate::test_support::{DummyBlock, SharedTestBackend, import_operation, shared_test_backend};
+
+	#[test]
+	fn least_recently_used_block_is_evicted() {
+		let mut cache = ValidatedBlockCache::new(2);
+		cache.note_valid(1);
+		cache.note_invalid(2);
+		assert_eq!(cache.validity(&1), Some(true));
+
+		cache.note_valid(3);
+		assert_eq!(cache.validity(&2), None);
+		assert_eq!(cache.validity(&1), Some(true));
+		assert_eq!(cache.validity(&3), Some(true));
+
+		cache.note_invalid(3);
+		cache.note_valid(4);
+		assert_eq!(cache.validity(&1), None);
+		assert_eq!(cache.validity(&3), Some(false));
+		assert_eq!(cache.validity(&4), Some(true));
+	}
+
+	struct CountingImporter {
+		backend: SharedTestBackend,
+		executions: usize,
+	}
+
+	impl BlockImporter for CountingImporter {
+		type Block = DummyBlock;
+		type Error = MemoryError;
+
+		fn import_block(&mut self, block: DummyBlock) -> Result<(), MemoryError> {
+			self.executions += 1;
+			self.backend.commit(import_operation(&[block]))
+		}
+	}
+
+	#[test]
+	fn block_from_several_peers_is_executed_once() {
+		let backend = shared_test_backend();
+		let mut importer = CachedImporter::new(
+			CountingImporter { backend: backend.clone(), executions: 0 },
+			16,
+		);
+
+		for _ in 0..2 {
+			importer.import_block(DummyBlock::new(1, 0)).unwrap();
+		}
+		assert_eq!(importer.importer.executions, 1);
+		assert!(backend.contains(&1).unwrap());
+
+		assert!(importer.import_block(DummyBlock::new(3, 2)).is_err());
+		importer.import_block(DummyBlock::new(2, 1)).unwrap();
+		importer.import_block(DummyBlock::new(3, 2)).unwrap();
+		assert_eq!(importer.importer.executions, 4);
+
+		importer.note_invalid(4);
+		assert!(importer.import_block(DummyBlock::new(4, 3)).is_err());
+		assert_eq!(importer.importer.executions, 4);
+	}
+}
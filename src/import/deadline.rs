@@ -0,0 +1,169 @@
+use std::{fmt, error as stderror};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+use crate::{Block, Auxiliary, BlockExecutor, AsExternalities};
+use crate::backend::{SharedMemoryBackend, ChainQuery, MemoryError};
+use crate::import::{BlockImporter, SharedBlockImporter, BestDepth, Error};
+
+#[derive(Debug)]
+/// Errors of a deadline importer
+pub enum DeadlineError<E> {
+	/// Execution did not finish before the deadline
+	Timeout,
+	/// Inner import error
+	Import(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for DeadlineError<E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl<E: fmt::Debug> stderror::Error for DeadlineError<E> { }
+
+/// An importer that executes each block on a worker thread, and gives up
+/// waiting once the deadline passes. Only the execution runs on the
+/// worker, without holding the import lock; the executed block is then
+/// imported with the deepest leaf as head.
+///
+/// A timed out execution is abandoned, not cancelled: the worker thread
+/// keeps running until the executor returns, but its result is dropped,
+/// and the block is not imported. Further imports are not blocked by it.
+pub struct DeadlineImporter<E, B: Block, A: Auxiliary<B>, S> {
+	executor: Arc<E>,
+	backend: SharedMemoryBackend<B, A, S>,
+	deadline: Duration,
+}
+
+impl<E, B: Block, A: Auxiliary<B>, S: Clone> DeadlineImporter<E, B, A, S> {
+	/// Create a new deadline importer.
+	pub fn new(executor: E, backend: SharedMemoryBackend<B, A, S>, deadline: Duration) -> Self {
+		Self { executor: Arc::new(executor), backend, deadline }
+	}
+}
+
+impl<E, B: Block, A: Auxiliary<B>, S: Clone> Clone for DeadlineImporter<E, B, A, S> {
+	fn clone(&self) -> Self {
+		Self {
+			executor: self.executor.clone(),
+			backend: self.backend.clone(),
+			deadline: self.deadline,
+		}
+	}
+}
+
+impl<E, B, A, S> BlockImporter for DeadlineImporter<E, B, A, S> where
+	E: BlockExecutor<Block=B> + Send + Sync + 'static,
+	E::Error: Send,
+	B: Block + Send + 'static,
+	B::Identifier: Send,
+	A: Auxiliary<B>,
+	S: AsExternalities<E::Externalities> + Clone + Send + 'static,
+{
+	type Block = B;
+	type Error = DeadlineError<Error>;
+
+	fn import_block(&mut self, block: B) -> Result<(), Self::Error> {
+		SharedBlockImporter::import_block(self, block)
+	}
+}
+
+impl<E, B, A, S> SharedBlockImporter for DeadlineImporter<E, B, A, S> where
+	E: BlockExecutor<Block=B> + Send + Sync + 'static,
+	E::Error: Send,
+	B: Block + Send + 'static,
+	B::Identifier: Send,
+	A: Auxiliary<B>,
+	S: AsExternalities<E::Externalities> + Clone + Send + 'static,
+{
+	fn import_block(&self, block: B) -> Result<(), DeadlineError<Error>> {
+		let backend_error = |e: MemoryError| DeadlineError::Import(Error::Backend(Box::new(e)));
+
+		self.backend.flush().map_err(backend_error)?;
+		let parent_id = block.parent_id()
+			.ok_or_else(|| backend_error(MemoryError::IsGenesis))?;
+		let mut state = self.backend.state_at(&parent_id).map_err(backend_error)?;
+
+		let executor = self.executor.clone();
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || {
+			executor.set_parent_context(state.as_externalities(), &parent_id);
+			let result = executor.execute_block(&block, state.as_externalities())
+				.map(|()| (block, state));
+			// The receiver is gone if the execution timed out.
+			let _ = sender.send(result);
+		});
+
+		let (block, state) = match receiver.recv_timeout(self.deadline) {
+			Ok(result) => result
+				.map_err(|e| DeadlineError::Import(Error::Executor(Box::new(e))))?,
+			Err(_) => return Err(DeadlineError::Timeout),
+		};
+
+		self.backend.import_executed(block, state, &BestDepth)
+			.map_err(DeadlineError::Import)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+	use core::convert::Infallible;
+	use crate::StorageExternalities;
+	use crate::test_support::{DummyBlock, CounterExecutor, CounterError, counter, shared_test_backend};
+
+	/// Counter executor hanging on blocks with the given id until the gate
+	/// is released.
+	struct GatedExecutor {
+		gate: Arc<Mutex<()>>,
+		gated: u64,
+	}
+
+	impl BlockExecutor for GatedExecutor {
+		type Error = CounterError;
+		type Block = DummyBlock;
+		type Externalities = dyn StorageExternalities<Infallible> + 'static;
+		type Event = ();
+
+		fn execute_block(
+			&self,
+			block: &DummyBlock,
+			state: &mut Self::Externalities,
+		) -> Result<(), CounterError> {
+			if block.id == self.gated {
+				drop(self.gate.lock().expect("Lock is poisoned"));
+			}
+			CounterExecutor::default().execute_block(block, state)
+		}
+	}
+
+	#[test]
+	fn timed_out_execution_does_not_block_imports() {
+		let gate = Arc::new(Mutex::new(()));
+		let backend = shared_test_backend();
+		let importer = DeadlineImporter::new(
+			GatedExecutor { gate: gate.clone(), gated: 1 },
+			backend.clone(),
+			Duration::from_millis(20),
+		);
+
+		let guard = gate.lock().unwrap();
+		match SharedBlockImporter::import_block(&importer, DummyBlock::new(1, 0)) {
+			Err(DeadlineError::Timeout) => (),
+			other => panic!("Expected timeout, got {:?}", other),
+		}
+
+		// The stuck execution holds no lock.
+		SharedBlockImporter::import_block(&importer.clone(), DummyBlock::new(2, 0)).unwrap();
+		assert_eq!(backend.head(), 2);
+		assert_eq!(counter(&mut backend.state_at(&2).unwrap()), 1);
+
+		drop(guard);
+		thread::sleep(Duration::from_millis(20));
+		assert!(!backend.contains(&1).unwrap());
+	}
+}
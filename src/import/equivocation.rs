@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{Block, AuthoredBlock};
+use crate::backend::{ChainQuery, TreeRoute};
+use crate::import::BlockImporter;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Two different blocks authored by the same author at the same depth.
+pub struct Equivocation<Author, Id> {
+	/// Author of both blocks
+	pub author: Author,
+	/// Depth of both blocks
+	pub depth: usize,
+	/// First block imported for the author at this depth, and the newly
+	/// imported block
+	pub ids: (Id, Id),
+}
+
+/// Default number of depths below the deepest imported block for which
+/// authored blocks are tracked.
+pub const DEFAULT_RETAINED_DEPTHS: usize = 1024;
+
+type AuthorOf<I> = <<I as BlockImporter>::Block as AuthoredBlock>::Author;
+type IdOf<I> = <<I as BlockImporter>::Block as Block>::Identifier;
+type AuthoredAtDepth<I> = HashMap<AuthorOf<I>, Vec<IdOf<I>>>;
+type EquivocationCallback<I> = Box<dyn FnMut(Equivocation<AuthorOf<I>, IdOf<I>>) + Send>;
+
+/// An importer that tracks which block each author has authored at each
+/// depth, and invokes a callback when an author is seen authoring two
+/// different blocks at the same depth.
+///
+/// Only the `DEFAULT_RETAINED_DEPTHS` depths below the deepest imported
+/// block are tracked, so that memory use stays bounded. Equivocations
+/// deeper than that are not detected.
+pub struct EquivocationImporter<I: BlockImporter, Ba> where
+	I::Block: AuthoredBlock,
+{
+	importer: I,
+	backend: Ba,
+	authored: BTreeMap<usize, AuthoredAtDepth<I>>,
+	retained_depths: usize,
+	on_equivocation: EquivocationCallback<I>,
+}
+
+impl<I: BlockImporter, Ba> EquivocationImporter<I, Ba> where
+	I::Block: AuthoredBlock,
+	Ba: ChainQuery<Block=I::Block>,
+{
+	/// Create a new equivocation detecting importer.
+	pub fn new<F>(importer: I, backend: Ba, on_equivocation: F) -> Self where
+		F: FnMut(Equivocation<AuthorOf<I>, IdOf<I>>) + Send + 'static,
+	{
+		Self {
+			importer,
+			backend,
+			authored: BTreeMap::new(),
+			retained_depths: DEFAULT_RETAINED_DEPTHS,
+			on_equivocation: Box::new(on_equivocation),
+		}
+	}
+
+	/// Set the number of depths below the deepest imported block for which
+	/// authored blocks are tracked.
+	pub fn with_retained_depths(self, retained_depths: usize) -> Self {
+		Self { retained_depths, ..self }
+	}
+
+	fn note_imported(&mut self, block: &I::Block) {
+		let depth = match block.parent_id() {
+			Some(parent_id) => match self.backend.depth_at(&parent_id) {
+				Ok(depth) => depth + 1,
+				Err(_) => return,
+			},
+			None => 0,
+		};
+		if let Some(deepest) = self.authored.keys().next_back() {
+			if depth + self.retained_depths < *deepest {
+				return
+			}
+		}
+		let author = block.author();
+		let id = block.id();
+
+		let ids = self.authored.entry(depth).or_default()
+			.entry(author.clone()).or_default();
+		if ids.contains(&id) {
+			return
+		}
+		ids.push(id.clone());
+
+		if ids.len() > 1 {
+			let first = ids[0].clone();
+			(self.on_equivocation)(Equivocation {
+				author,
+				depth,
+				ids: (first, id),
+			});
+		}
+
+		let deepest = *self.authored.keys().next_back()
+			.expect("Authored block was just inserted; qed");
+		let lowest_retained = deepest.saturating_sub(self.retained_depths);
+		self.authored = self.authored.split_off(&lowest_retained);
+	}
+}
+
+impl<I: BlockImporter, Ba> BlockImporter for EquivocationImporter<I, Ba> where
+	I::Block: AuthoredBlock,
+	Ba: ChainQuery<Block=I::Block>,
+{
+	type Block = I::Block;
+	type Error = I::Error;
+
+	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
+		self.importer.import_block(block.clone())?;
+		self.note_imported(&block);
+		Ok(())
+	}
+
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		let route = self.importer.import_block_with_route(block.clone())?;
+		self.note_imported(&block);
+		Ok(route)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+	use super::*;
+	use crate::backend::{
+		SharedCommittable, MemoryError, SharedMemoryBackend, KeyValueMemoryState,
+		Operation, ImportOperation,
+	};
+	use crate::test_support::DummyBlock;
+
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	struct AuthoredDummyBlock {
+		block: DummyBlock,
+		author: u32,
+	}
+
+	impl Block for AuthoredDummyBlock {
+		type Identifier = u64;
+
+		fn id(&self) -> u64 { self.block.id }
+		fn parent_id(&self) -> Option<u64> { self.block.parent }
+	}
+
+	impl AuthoredBlock for AuthoredDummyBlock {
+		type Author = u32;
+
+		fn author(&self) -> u32 { self.author }
+	}
+
+	fn authored(id: u64, parent: u64, author: u32) -> AuthoredDummyBlock {
+		AuthoredDummyBlock { block: DummyBlock::new(id, parent), author }
+	}
+
+	type AuthoredBackend = SharedMemoryBackend<AuthoredDummyBlock, (), KeyValueMemoryState>;
+
+	struct BackendImporter(AuthoredBackend);
+
+	impl BlockImporter for BackendImporter {
+		type Block = AuthoredDummyBlock;
+		type Error = MemoryError;
+
+		fn import_block(&mut self, block: AuthoredDummyBlock) -> Result<(), MemoryError> {
+			self.0.commit(Operation {
+				import_block: vec![ImportOperation { block, state: Default::default() }],
+				..Default::default()
+			})
+		}
+	}
+
+	type Equivocations = Arc<Mutex<Vec<Equivocation<u32, u64>>>>;
+
+	fn equivocation_importer(
+		retained_depths: usize,
+	) -> (EquivocationImporter<BackendImporter, AuthoredBackend>, Equivocations) {
+		let backend = AuthoredBackend::new_with_genesis(
+			AuthoredDummyBlock { block: DummyBlock::genesis(), author: 0 },
+			Default::default(),
+		);
+		let equivocations = Arc::new(Mutex::new(Vec::new()));
+		let noted = equivocations.clone();
+		let importer = EquivocationImporter::new(
+			BackendImporter(backend.clone()),
+			backend,
+			move |equivocation| noted.lock().unwrap().push(equivocation),
+		).with_retained_depths(retained_depths);
+		(importer, equivocations)
+	}
+
+	#[test]
+	fn same_author_at_same_depth_is_equivocation() {
+		let (mut importer, equivocations) = equivocation_importer(DEFAULT_RETAINED_DEPTHS);
+		importer.import_block(authored(1, 0, 1)).unwrap();
+		importer.import_block(authored(2, 0, 2)).unwrap();
+		assert!(equivocations.lock().unwrap().is_empty());
+
+		importer.import_block(authored(3, 0, 1)).unwrap();
+		assert_eq!(*equivocations.lock().unwrap(), vec![Equivocation {
+			author: 1,
+			depth: 1,
+			ids: (1, 3),
+		}]);
+	}
+
+	#[test]
+	fn authored_blocks_below_retained_depths_are_pruned() {
+		let (mut importer, equivocations) = equivocation_importer(2);
+		importer.import_block(authored(1, 0, 1)).unwrap();
+		for id in 2..6 {
+			importer.import_block(authored(id, id - 1, 2)).unwrap();
+		}
+		assert_eq!(importer.authored.keys().cloned().collect::<Vec<_>>(), vec![3, 4, 5]);
+
+		importer.import_block(authored(6, 0, 1)).unwrap();
+		importer.import_block(authored(7, 3, 1)).unwrap();
+		importer.import_block(authored(8, 3, 2)).unwrap();
+		assert_eq!(*equivocations.lock().unwrap(), vec![Equivocation {
+			author: 2,
+			depth: 4,
+			ids: (4, 8),
+		}]);
+	}
+}
@@ -0,0 +1,66 @@
+use crate::Block;
+use crate::backend::ChainQuery;
+
+/// Fork choice rule, deciding which block should be the head.
+pub trait ForkChoice<Ba: ChainQuery> {
+	/// Whether the candidate block is preferred over the current one.
+	fn is_better(
+		&self,
+		backend: &Ba,
+		candidate: &<Ba::Block as Block>::Identifier,
+		current: &<Ba::Block as Block>::Identifier,
+	) -> Result<bool, Ba::Error>;
+}
+
+/// Fork choice rule preferring the deepest block.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BestDepth;
+
+impl<Ba: ChainQuery> ForkChoice<Ba> for BestDepth {
+	fn is_better(
+		&self,
+		backend: &Ba,
+		candidate: &<Ba::Block as Block>::Identifier,
+		current: &<Ba::Block as Block>::Identifier,
+	) -> Result<bool, Ba::Error> {
+		Ok(backend.depth_at(candidate)? > backend.depth_at(current)?)
+	}
+}
+
+/// Evaluate the fork choice over all leaves, and return the winner. The
+/// current head is kept unless a leaf is strictly better.
+pub fn best_leaf<Ba: ChainQuery, F: ForkChoice<Ba>>(
+	backend: &Ba,
+	fork_choice: &F,
+) -> Result<<Ba::Block as Block>::Identifier, Ba::Error> {
+	let mut best = backend.head();
+
+	for leaf in backend.leaves()? {
+		if fork_choice.is_better(backend, &leaf, &best)? {
+			best = leaf;
+		}
+	}
+
+	Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::backend::ChainQuery;
+	use crate::test_support::{DummyBlock, CounterExecutor, import_shared_chain, shared_test_backend};
+
+	#[test]
+	fn import_moves_head_to_best_existing_leaf() {
+		let backend = shared_test_backend();
+		import_shared_chain(&backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], false).unwrap();
+		import_shared_chain(&backend, &[DummyBlock::new(3, 0)], true).unwrap();
+		assert_eq!(backend.head(), 3);
+
+		// The imported block is no deeper than the head, but the head moves
+		// to the deepest leaf, on another fork.
+		assert!(backend.import_and_set_head(&CounterExecutor::default(), DummyBlock::new(4, 0)).unwrap());
+		assert_eq!(backend.head(), 2);
+		assert!(backend.is_canon(&1).unwrap());
+		assert!(!backend.is_canon(&3).unwrap());
+	}
+}
@@ -0,0 +1,76 @@
+/// Provider of a part of the inherent data of a block, such as a timestamp
+/// or randomness.
+pub trait InherentDataProvider<I> {
+	/// Fill in the provided part of the inherent.
+	fn provide(&self, inherent: &mut I);
+}
+
+impl<I, F: Fn(&mut I)> InherentDataProvider<I> for F {
+	fn provide(&self, inherent: &mut I) {
+		self(inherent)
+	}
+}
+
+/// Collection of inherent data providers, composing the inherent of a block
+/// from all registered providers, in registration order.
+pub struct InherentDataProviders<I> {
+	providers: Vec<Box<dyn InherentDataProvider<I> + Send + Sync>>,
+}
+
+impl<I> Default for InherentDataProviders<I> {
+	fn default() -> Self {
+		Self { providers: Vec::new() }
+	}
+}
+
+impl<I: Default> InherentDataProviders<I> {
+	/// Create an empty provider collection.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Register a new provider.
+	pub fn register<P>(&mut self, provider: P) where
+		P: InherentDataProvider<I> + Send + Sync + 'static,
+	{
+		self.providers.push(Box::new(provider));
+	}
+
+	/// Compose the inherent, starting from its default value.
+	pub fn provide(&self) -> I {
+		let mut inherent = I::default();
+		for provider in &self.providers {
+			provider.provide(&mut inherent);
+		}
+		inherent
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug, Default, PartialEq, Eq)]
+	struct TestInherent {
+		timestamp: u64,
+		seed: u64,
+	}
+
+	struct TimestampProvider(u64);
+
+	impl InherentDataProvider<TestInherent> for TimestampProvider {
+		fn provide(&self, inherent: &mut TestInherent) {
+			inherent.timestamp = self.0;
+		}
+	}
+
+	#[test]
+	fn inherent_is_composed_from_providers() {
+		let mut providers = InherentDataProviders::new();
+		assert_eq!(providers.provide(), TestInherent::default());
+
+		providers.register(TimestampProvider(1_600_000_000));
+		providers.register(|inherent: &mut TestInherent| inherent.seed = inherent.timestamp ^ 42);
+		assert_eq!(providers.provide(), TestInherent { timestamp: 1_600_000_000, seed: 1_600_000_000 ^ 42 });
+	}
+}
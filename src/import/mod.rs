@@ -2,31 +2,200 @@
 
 mod action;
 mod traits;
+mod producer;
+mod recording;
+mod fork_choice;
+mod notify;
+mod inherent;
+mod cache;
+mod ordered;
+mod deadline;
+mod equivocation;
 
 pub use self::action::ImportAction;
 pub use self::traits::{RawImporter, SharedRawImporter, BlockImporter, SharedBlockImporter};
+pub use self::producer::{BlockProducer, PendingBlock};
+pub use self::recording::RecordingImporter;
+pub use self::fork_choice::{ForkChoice, BestDepth, best_leaf};
+pub use self::notify::NotifyImporter;
+pub use self::inherent::{InherentDataProvider, InherentDataProviders};
+pub use self::cache::{ValidatedBlockCache, CachedImporter};
+pub use self::ordered::OrderedImporter;
+pub use self::deadline::{DeadlineImporter, DeadlineError};
+pub use self::equivocation::{EquivocationImporter, Equivocation};
 
-use std::sync::{Arc, Mutex};
+use std::{fmt, error as stderror};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::backend::{lock_or_recover, TreeRoute};
+use crate::{BlockExecutor, VersionedBlock};
+
+#[derive(Debug)]
+/// Import and block production errors
+pub enum Error {
+	/// Backend error
+	Backend(Box<dyn stderror::Error>),
+	/// Executor error
+	Executor(Box<dyn stderror::Error>),
+	/// Block has reached its maximum weight
+	BlockFull,
+	/// Parent block to build on does not exist
+	UnknownParent,
+	/// Parent block to build on is not canonical
+	NonCanonParent,
+	/// Block spec version is not the executor's
+	IncompatibleSpecVersion {
+		/// Spec version of the executor
+		expected: u32,
+		/// Spec version of the block
+		found: u32,
+	},
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl stderror::Error for Error { }
+
+/// Check that a block is of the executor's spec version.
+pub fn check_spec_version<E>(executor: &E, block: &E::Block) -> Result<(), Error> where
+	E: BlockExecutor,
+	E::Block: VersionedBlock,
+{
+	let expected = executor.spec_version();
+	let found = block.spec_version();
+	if expected != found {
+		return Err(Error::IncompatibleSpecVersion { expected, found })
+	}
+
+	Ok(())
+}
 
 /// An importer that can be shared across threads.
+///
+/// By default, all imports are serialized through a single importer. With
+/// `with_concurrency`, up to `n` imports run concurrently, each on its own
+/// clone of the importer.
 pub struct MutexImporter<I> {
-	importer: Arc<Mutex<I>>,
+	importers: Arc<Vec<Mutex<I>>>,
+	permits: Arc<(Mutex<usize>, Condvar)>,
+	recover_poison: Arc<AtomicBool>,
 }
 
 impl<I> MutexImporter<I> {
 	/// Create a new shared import block.
 	pub fn new(importer: I) -> Self {
+		Self::from_importers(vec![importer])
+	}
+
+	/// Create a new shared import block, allowing up to `concurrency`
+	/// imports to run at the same time. The backend is expected to
+	/// serialize commits itself.
+	///
+	/// Each concurrent import runs on its own clone of the importer, so
+	/// state the importer keeps per instance, rather than behind a shared
+	/// handle, is not seen by the other clones.
+	pub fn with_concurrency(importer: I, concurrency: usize) -> Self where
+		I: Clone,
+	{
+		let mut importers = (1..concurrency.max(1))
+			.map(|_| importer.clone())
+			.collect::<Vec<_>>();
+		importers.push(importer);
+		Self::from_importers(importers)
+	}
+
+	/// Set whether to recover from a poisoned lock instead of panicking.
+	/// The setting is shared with all clones of the importer.
+	pub fn with_recover_poison(self, recover_poison: bool) -> Self {
+		self.recover_poison.store(recover_poison, Ordering::SeqCst);
+		self
+	}
+
+	fn from_importers(importers: Vec<I>) -> Self {
+		let permits = importers.len();
+
 		Self {
-			importer: Arc::new(Mutex::new(importer)),
+			importers: Arc::new(importers.into_iter().map(Mutex::new).collect()),
+			permits: Arc::new((Mutex::new(permits), Condvar::new())),
+			recover_poison: Arc::new(AtomicBool::new(false)),
 		}
 	}
+
+	fn lock(&self) -> ImporterGuard<'_, I> {
+		let permit = Permit::acquire(&self.permits);
+
+		for importer in self.importers.iter() {
+			let guard = match importer.try_lock() {
+				Ok(guard) => guard,
+				Err(TryLockError::Poisoned(err)) => lock_or_recover(Err(err), self.recover_poison.load(Ordering::SeqCst)),
+				Err(TryLockError::WouldBlock) => continue,
+			};
+
+			return ImporterGuard { guard, _permit: permit }
+		}
+
+		unreachable!("Each permit corresponds to one importer; \
+			a permit is held; \
+			thus at least one importer is unlocked; qed")
+	}
 }
 
 impl<I> Clone for MutexImporter<I> {
 	fn clone(&self) -> Self {
 		Self {
-			importer: self.importer.clone(),
+			importers: self.importers.clone(),
+			permits: self.permits.clone(),
+			recover_poison: self.recover_poison.clone(),
+		}
+	}
+}
+
+struct Permit<'a>(&'a (Mutex<usize>, Condvar));
+
+impl<'a> Permit<'a> {
+	fn acquire(permits: &'a (Mutex<usize>, Condvar)) -> Self {
+		let (available, condvar) = permits;
+		let mut available = available.lock()
+			.expect("Permit lock is never held across imports; qed");
+		while *available == 0 {
+			available = condvar.wait(available)
+				.expect("Permit lock is never held across imports; qed");
 		}
+		*available -= 1;
+
+		Permit(permits)
+	}
+}
+
+impl<'a> Drop for Permit<'a> {
+	fn drop(&mut self) {
+		let (available, condvar) = self.0;
+		*available.lock().expect("Permit lock is never held across imports; qed") += 1;
+		condvar.notify_one();
+	}
+}
+
+struct ImporterGuard<'a, I> {
+	guard: MutexGuard<'a, I>,
+	_permit: Permit<'a>,
+}
+
+impl<'a, I> Deref for ImporterGuard<'a, I> {
+	type Target = I;
+
+	fn deref(&self) -> &I {
+		&self.guard
+	}
+}
+
+impl<'a, I> DerefMut for ImporterGuard<'a, I> {
+	fn deref_mut(&mut self) -> &mut I {
+		&mut self.guard
 	}
 }
 
@@ -37,6 +206,13 @@ impl<I: BlockImporter> BlockImporter for MutexImporter<I> {
 	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
 		SharedBlockImporter::import_block(self, block)
 	}
+
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		SharedBlockImporter::import_block_with_route(self, block)
+	}
 }
 
 impl<I: BlockImporter> SharedBlockImporter for MutexImporter<I> {
@@ -44,9 +220,17 @@ impl<I: BlockImporter> SharedBlockImporter for MutexImporter<I> {
 		&self,
 		block: <Self as BlockImporter>::Block
 	) -> Result<(), <Self as BlockImporter>::Error> {
-		self.importer.lock().expect("Lock is poisoned")
+		self.lock()
 			.import_block(block)
 	}
+
+	fn import_block_with_route(
+		&self,
+		block: <Self as BlockImporter>::Block
+	) -> Result<Option<TreeRoute<<Self as BlockImporter>::Block>>, <Self as BlockImporter>::Error> {
+		self.lock()
+			.import_block_with_route(block)
+	}
 }
 
 impl<I: RawImporter> RawImporter for MutexImporter<I> {
@@ -66,7 +250,99 @@ impl<I: RawImporter> SharedRawImporter for MutexImporter<I> {
 		&self,
 		raw: Self::Operation
 	) -> Result<(), <Self as RawImporter>::Error> {
-		self.importer.lock().expect("Lock is poisoned")
+		self.lock()
 			.import_raw(raw)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use crate::test_support::{DummyBlock, CounterError};
+
+	/// Importer waiting, for a bounded time, until another import runs
+	/// concurrently, and recording the maximum number of concurrent
+	/// imports.
+	#[derive(Clone, Default)]
+	struct ConcurrentImporter {
+		active: Arc<AtomicUsize>,
+		max_active: Arc<AtomicUsize>,
+	}
+
+	impl BlockImporter for ConcurrentImporter {
+		type Block = DummyBlock;
+		type Error = CounterError;
+
+		fn import_block(&mut self, _block: DummyBlock) -> Result<(), CounterError> {
+			let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+			self.max_active.fetch_max(active, Ordering::SeqCst);
+
+			let start = Instant::now();
+			while self.max_active.load(Ordering::SeqCst) < 2 &&
+				start.elapsed() < Duration::from_millis(200)
+			{
+				thread::yield_now();
+			}
+
+			self.active.fetch_sub(1, Ordering::SeqCst);
+			Ok(())
+		}
+	}
+
+	fn import_on_threads(importer: &MutexImporter<ConcurrentImporter>, n: u64) {
+		let handles = (1..=n).map(|id| {
+			let importer = importer.clone();
+			thread::spawn(move || {
+				SharedBlockImporter::import_block(&importer, DummyBlock::new(id, 0)).unwrap()
+			})
+		}).collect::<Vec<_>>();
+
+		for handle in handles {
+			handle.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn imports_run_concurrently_up_to_limit() {
+		let inner = ConcurrentImporter::default();
+		import_on_threads(&MutexImporter::new(inner.clone()), 2);
+		assert_eq!(inner.max_active.load(Ordering::SeqCst), 1);
+
+		let inner = ConcurrentImporter::default();
+		import_on_threads(&MutexImporter::with_concurrency(inner.clone(), 2), 4);
+		assert_eq!(inner.max_active.load(Ordering::SeqCst), 2);
+	}
+
+	#[derive(Clone)]
+	struct PanickingImporter;
+
+	impl BlockImporter for PanickingImporter {
+		type Block = DummyBlock;
+		type Error = CounterError;
+
+		fn import_block(&mut self, block: DummyBlock) -> Result<(), CounterError> {
+			if block.id == 1 {
+				panic!("Importing block 1 panics");
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn recover_poison_is_shared_with_clones() {
+		let importer = MutexImporter::new(PanickingImporter);
+		let clone = importer.clone();
+		let importer = importer.with_recover_poison(true);
+
+		let panicking = clone.clone();
+		assert!(thread::spawn(move || {
+			SharedBlockImporter::import_block(&panicking, DummyBlock::new(1, 0))
+		}).join().is_err());
+
+		SharedBlockImporter::import_block(&clone, DummyBlock::new(2, 0)).unwrap();
+		SharedBlockImporter::import_block(&importer, DummyBlock::new(3, 0)).unwrap();
+	}
+}
@@ -0,0 +1,104 @@
+use crate::Block;
+use crate::backend::{ChainQuery, TreeRoute};
+use crate::import::BlockImporter;
+
+type ImportedCallback<B> = Box<dyn FnMut(&B, bool) + Send>;
+
+/// An importer that invokes a callback after each successful import, with
+/// the imported block and whether it became the new head.
+pub struct NotifyImporter<I: BlockImporter, Ba> {
+	importer: I,
+	backend: Ba,
+	on_imported: ImportedCallback<I::Block>,
+}
+
+impl<I: BlockImporter, Ba> NotifyImporter<I, Ba> where
+	Ba: ChainQuery<Block=I::Block>,
+{
+	/// Create a new notifying importer.
+	pub fn new<F>(importer: I, backend: Ba, on_imported: F) -> Self where
+		F: FnMut(&I::Block, bool) + Send + 'static,
+	{
+		Self {
+			importer,
+			backend,
+			on_imported: Box::new(on_imported),
+		}
+	}
+}
+
+impl<I: BlockImporter, Ba> BlockImporter for NotifyImporter<I, Ba> where
+	Ba: ChainQuery<Block=I::Block>,
+{
+	type Block = I::Block;
+	type Error = I::Error;
+
+	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
+		self.importer.import_block(block.clone())?;
+		let is_head = self.backend.head() == block.id();
+		(self.on_imported)(&block, is_head);
+		Ok(())
+	}
+
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		let route = self.importer.import_block_with_route(block.clone())?;
+		let is_head = self.backend.head() == block.id();
+		(self.on_imported)(&block, is_head);
+		Ok(route)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+	use super::*;
+	use crate::import::Error;
+	use crate::test_support::{DummyBlock, SharedTestBackend, CounterExecutor, shared_test_backend};
+
+	/// Importer executing blocks, and setting the longest chain as head.
+	struct LongestChainImporter(SharedTestBackend);
+
+	impl BlockImporter for LongestChainImporter {
+		type Block = DummyBlock;
+		type Error = Error;
+
+		fn import_block(&mut self, block: DummyBlock) -> Result<(), Error> {
+			self.0.import_and_set_head(&CounterExecutor::default(), block)?;
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn callback_fires_once_per_block() {
+		let backend = shared_test_backend();
+		let notified = Arc::new(Mutex::new(Vec::new()));
+		let noted = notified.clone();
+		let mut importer = NotifyImporter::new(
+			LongestChainImporter(backend.clone()),
+			backend,
+			move |block: &DummyBlock, is_head| noted.lock().unwrap().push((block.id, is_head)),
+		);
+
+		for block in &[
+			DummyBlock::new(1, 0),
+			DummyBlock::new(2, 1),
+			DummyBlock::new(3, 0),
+			DummyBlock::new(4, 3),
+			DummyBlock::new(5, 4),
+		] {
+			importer.import_block(block.clone()).unwrap();
+		}
+		assert!(importer.import_block(DummyBlock::new(7, 6)).is_err());
+
+		assert_eq!(*notified.lock().unwrap(), vec![
+			(1, true),
+			(2, true),
+			(3, false),
+			(4, false),
+			(5, true),
+		]);
+	}
+}
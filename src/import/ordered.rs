@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use crate::Block;
+use crate::backend::ChainQuery;
+use crate::import::BlockImporter;
+
+/// An importer that only forwards blocks to the inner importer once their
+/// parent is in the backend. Blocks arriving before their parent are
+/// buffered, and forwarded right after the parent is imported.
+pub struct OrderedImporter<I: BlockImporter, Ba> {
+	importer: I,
+	backend: Ba,
+	pending: HashMap<<I::Block as Block>::Identifier, Vec<I::Block>>,
+	pending_count: usize,
+	max_pending: usize,
+	dropped_blocks: usize,
+}
+
+impl<I: BlockImporter, Ba> OrderedImporter<I, Ba> where
+	Ba: ChainQuery<Block=I::Block>,
+{
+	/// Create a new ordered importer, buffering at most `max_pending`
+	/// blocks whose parent is missing. Further orphan blocks are dropped,
+	/// and counted in `dropped_blocks`.
+	pub fn new(importer: I, backend: Ba, max_pending: usize) -> Self {
+		Self {
+			importer,
+			backend,
+			pending: HashMap::new(),
+			pending_count: 0,
+			max_pending,
+			dropped_blocks: 0,
+		}
+	}
+
+	/// Number of buffered blocks waiting for their parent.
+	pub fn pending_count(&self) -> usize {
+		self.pending_count
+	}
+
+	/// Number of orphan blocks dropped because the buffer was full.
+	pub fn dropped_blocks(&self) -> usize {
+		self.dropped_blocks
+	}
+
+	fn is_ready(&self, block: &I::Block) -> bool {
+		match block.parent_id() {
+			Some(parent_id) => self.backend.contains(&parent_id).unwrap_or(false),
+			None => true,
+		}
+	}
+}
+
+impl<I: BlockImporter, Ba> BlockImporter for OrderedImporter<I, Ba> where
+	Ba: ChainQuery<Block=I::Block>,
+{
+	type Block = I::Block;
+	type Error = I::Error;
+
+	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
+		if !self.is_ready(&block) {
+			if self.pending_count < self.max_pending {
+				let parent_id = block.parent_id()
+					.expect("Block without parent is always ready; qed");
+				self.pending.entry(parent_id).or_default().push(block);
+				self.pending_count += 1;
+			} else {
+				self.dropped_blocks += 1;
+			}
+			return Ok(())
+		}
+
+		self.importer.import_block(block.clone())?;
+
+		// Forward buffered descendants. Descendants of a block that fails to
+		// import stay buffered, and the first error is returned.
+		let mut result = Ok(());
+		let mut imported = VecDeque::new();
+		imported.push_back(block.id());
+		while let Some(id) = imported.pop_front() {
+			for child in self.pending.remove(&id).unwrap_or_default() {
+				self.pending_count -= 1;
+				let child_id = child.id();
+				match self.importer.import_block(child) {
+					Ok(()) => imported.push_back(child_id),
+					Err(e) => if result.is_ok() {
+						result = Err(e);
+					},
+				}
+			}
+		}
+
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+	use super::*;
+	use crate::backend::{SharedCommittable, MemoryError};
+	use crate::test_support::{DummyBlock, SharedTestBackend, import_operation, shared_test_backend};
+
+	/// Importer failing to import blocks whose parent is missing.
+	struct StrictImporter {
+		backend: SharedTestBackend,
+		imported: Arc<Mutex<Vec<u64>>>,
+	}
+
+	impl BlockImporter for StrictImporter {
+		type Block = DummyBlock;
+		type Error = MemoryError;
+
+		fn import_block(&mut self, block: DummyBlock) -> Result<(), MemoryError> {
+			let id = block.id;
+			self.backend.commit(import_operation(&[block]))?;
+			self.imported.lock().expect("Lock is poisoned").push(id);
+			Ok(())
+		}
+	}
+
+	fn ordered_importer(
+		max_pending: usize,
+	) -> (OrderedImporter<StrictImporter, SharedTestBackend>, Arc<Mutex<Vec<u64>>>) {
+		let backend = shared_test_backend();
+		let imported = Arc::new(Mutex::new(Vec::new()));
+		let importer = StrictImporter { backend: backend.clone(), imported: imported.clone() };
+		(OrderedImporter::new(importer, backend, max_pending), imported)
+	}
+
+	#[test]
+	fn reversed_chain_is_imported_in_order() {
+		let (mut importer, imported) = ordered_importer(16);
+		for id in (1..=5).rev() {
+			importer.import_block(DummyBlock::new(id, id - 1)).unwrap();
+		}
+
+		assert_eq!(*imported.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+		assert_eq!(importer.pending_count(), 0);
+		assert_eq!(importer.dropped_blocks(), 0);
+	}
+
+	#[test]
+	fn orphans_past_max_pending_are_counted() {
+		let (mut importer, imported) = ordered_importer(2);
+		for id in (2..=5).rev() {
+			importer.import_block(DummyBlock::new(id, id - 1)).unwrap();
+		}
+		assert_eq!(importer.pending_count(), 2);
+		assert_eq!(importer.dropped_blocks(), 2);
+
+		importer.import_block(DummyBlock::new(1, 0)).unwrap();
+		assert_eq!(*imported.lock().unwrap(), vec![1]);
+		assert_eq!(importer.pending_count(), 2);
+	}
+}
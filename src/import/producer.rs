@@ -0,0 +1,383 @@
+use crate::{Block, ExtrinsicBuilder, Sealer, AsExternalities, Weight};
+use crate::backend::{Store, ChainQuery};
+use crate::import::{Error, InherentDataProviders};
+
+/// Block producer, building new blocks on top of the current head.
+///
+/// The producer only initializes, applies extrinsics and finalizes the
+/// block. Sealing is left to the caller, as it is usually consensus
+/// specific.
+pub struct BlockProducer<E, Ba> {
+	executor: E,
+	backend: Ba,
+}
+
+impl<E: Clone, Ba: Clone> Clone for BlockProducer<E, Ba> {
+	fn clone(&self) -> Self {
+		Self {
+			executor: self.executor.clone(),
+			backend: self.backend.clone(),
+		}
+	}
+}
+
+impl<E, Ba> BlockProducer<E, Ba> where
+	E: ExtrinsicBuilder,
+	Ba: ChainQuery + Store<Block=E::Block>,
+	Ba::State: AsExternalities<E::Externalities>,
+{
+	/// Create a new block producer.
+	pub fn new(executor: E, backend: Ba) -> Self {
+		Self { executor, backend }
+	}
+
+	/// Get the associated executor of the producer.
+	pub fn executor(&self) -> &E {
+		&self.executor
+	}
+
+	/// Get the associated backend of the producer.
+	pub fn backend(&self) -> &Ba {
+		&self.backend
+	}
+
+	/// Produce one block on top of the current head, applying all given
+	/// extrinsics. Returns the unsealed block, together with its post
+	/// state. Fails with `Error::BlockFull` if the total weight of the
+	/// extrinsics exceeds the maximum block weight.
+	pub fn produce_one<X: IntoIterator<Item=E::Extrinsic>>(
+		&self,
+		inherent: E::Inherent,
+		extrinsics: X,
+	) -> Result<(E::BuildBlock, Ba::State), Error> {
+		let head = self.backend.head();
+		let parent_block = self.backend.block_at(&head)
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		let mut state = self.backend.state_at(&head)
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		self.executor.set_parent_context(state.as_externalities(), &head);
+
+		let mut block = self.executor.initialize_block(
+			&parent_block, state.as_externalities(), inherent
+		).map_err(|e| Error::Executor(Box::new(e)))?;
+
+		let max_weight = self.executor.max_block_weight();
+		let mut weight: Weight = 0;
+		for extrinsic in extrinsics {
+			weight = weight.saturating_add(self.executor.apply_extrinsic(
+				&mut block, extrinsic, state.as_externalities()
+			).map_err(|e| Error::Executor(Box::new(e)))?);
+
+			if weight > max_weight {
+				return Err(Error::BlockFull)
+			}
+		}
+
+		self.executor.finalize_block(
+			&mut block, state.as_externalities()
+		).map_err(|e| Error::Executor(Box::new(e)))?;
+
+		Ok((block, state))
+	}
+
+	/// Begin building a block on top of the current head, applying
+	/// extrinsics one at a time through the returned pending block.
+	pub fn begin_block(
+		&self,
+		inherent: E::Inherent,
+	) -> Result<PendingBlock<'_, E, Ba::State>, Error> {
+		self.begin_block_on(&self.backend.head(), inherent, false)
+	}
+
+	/// Begin building a block on top of the given parent. Fails with
+	/// `Error::UnknownParent` if the parent does not exist, and with
+	/// `Error::NonCanonParent` if it is not canonical, unless
+	/// `allow_non_canon` is set.
+	pub fn begin_block_on(
+		&self,
+		parent: &<E::Block as Block>::Identifier,
+		inherent: E::Inherent,
+		allow_non_canon: bool,
+	) -> Result<PendingBlock<'_, E, Ba::State>, Error> {
+		if !self.backend.contains(parent).map_err(|e| Error::Backend(Box::new(e)))? {
+			return Err(Error::UnknownParent)
+		}
+		if !allow_non_canon &&
+			!self.backend.is_canon(parent).map_err(|e| Error::Backend(Box::new(e)))?
+		{
+			return Err(Error::NonCanonParent)
+		}
+
+		let parent_block = self.backend.block_at(parent)
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		let mut state = self.backend.state_at(parent)
+			.map_err(|e| Error::Backend(Box::new(e)))?;
+		self.executor.set_parent_context(state.as_externalities(), parent);
+
+		let block = self.executor.initialize_block(
+			&parent_block, state.as_externalities(), inherent
+		).map_err(|e| Error::Executor(Box::new(e)))?;
+
+		Ok(PendingBlock {
+			executor: &self.executor,
+			block,
+			state,
+			weight: 0,
+			last: None,
+		})
+	}
+
+	/// Produce one block on top of the current head, composing its inherent
+	/// from the given providers.
+	pub fn produce_with_providers<X: IntoIterator<Item=E::Extrinsic>>(
+		&self,
+		providers: &InherentDataProviders<E::Inherent>,
+		extrinsics: X,
+	) -> Result<(E::BuildBlock, Ba::State), Error> where
+		E::Inherent: Default,
+	{
+		self.produce_one(providers.provide(), extrinsics)
+	}
+
+	/// Produce one block on top of the current head, and seal it with the
+	/// executor. Returns the sealed block, together with its post state.
+	pub fn produce_sealed<X: IntoIterator<Item=E::Extrinsic>>(
+		&self,
+		inherent: E::Inherent,
+		extrinsics: X,
+	) -> Result<(E::Block, Ba::State), Error> where
+		E: Sealer<Unsealed=E::BuildBlock, Sealed=E::Block>,
+	{
+		let (block, state) = self.produce_one(inherent, extrinsics)?;
+		Ok((self.executor.seal(block), state))
+	}
+}
+
+/// Block being built by a producer. Extrinsics are applied one at a time,
+/// and the last applied extrinsic can be rolled back.
+pub struct PendingBlock<'a, E: ExtrinsicBuilder, S> {
+	executor: &'a E,
+	block: E::BuildBlock,
+	state: S,
+	weight: Weight,
+	last: Option<(E::BuildBlock, S, Weight)>,
+}
+
+impl<'a, E, S> PendingBlock<'a, E, S> where
+	E: ExtrinsicBuilder,
+	E::BuildBlock: Clone,
+	S: Clone + AsExternalities<E::Externalities>,
+{
+	/// Get the current state of the pending block.
+	pub fn state(&self) -> &S {
+		&self.state
+	}
+
+	/// Get the cumulative weight of applied extrinsics.
+	pub fn weight(&self) -> Weight {
+		self.weight
+	}
+
+	/// Apply an extrinsic, returning its weight. Fails with
+	/// `Error::BlockFull` if the extrinsic would take the block over its
+	/// maximum weight. On failure, the pending block is left unchanged.
+	pub fn apply(&mut self, extrinsic: E::Extrinsic) -> Result<Weight, Error> {
+		let snapshot = (self.block.clone(), self.state.clone(), self.weight);
+		match self.executor.apply_extrinsic(
+			&mut self.block, extrinsic, self.state.as_externalities()
+		) {
+			Ok(weight) if self.weight.saturating_add(weight) > self.executor.max_block_weight() => {
+				let (block, state, _) = snapshot;
+				self.block = block;
+				self.state = state;
+				Err(Error::BlockFull)
+			},
+			Ok(weight) => {
+				self.weight = self.weight.saturating_add(weight);
+				self.last = Some(snapshot);
+				Ok(weight)
+			},
+			Err(e) => {
+				let (block, state, _) = snapshot;
+				self.block = block;
+				self.state = state;
+				Err(Error::Executor(Box::new(e)))
+			},
+		}
+	}
+
+	/// Roll back the last applied extrinsic. Returns false if there is
+	/// nothing to roll back, either because no extrinsic was applied or
+	/// because the last one was already rolled back.
+	pub fn rollback_last(&mut self) -> bool {
+		match self.last.take() {
+			Some((block, state, weight)) => {
+				self.block = block;
+				self.state = state;
+				self.weight = weight;
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Finalize the block, returning the unsealed block together with its
+	/// post state.
+	pub fn finalize(mut self) -> Result<(E::BuildBlock, S), Error> {
+		self.executor.finalize_block(
+			&mut self.block, self.state.as_externalities()
+		).map_err(|e| Error::Executor(Box::new(e)))?;
+
+		Ok((self.block, self.state))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use core::convert::Infallible;
+	use crate::{BlockExecutor, StorageExternalities};
+	use crate::import::InherentDataProvider;
+	use crate::test_support::{DummyBlock, CounterExecutor, CounterError, counter, import_chain, test_backend};
+
+	const TIMESTAMP_KEY: &[u8] = b"timestamp";
+
+	/// Counter executor whose inherent is a timestamp, written to the state
+	/// when the block is initialized.
+	struct TimestampExecutor;
+
+	impl BlockExecutor for TimestampExecutor {
+		type Error = CounterError;
+		type Block = DummyBlock;
+		type Externalities = dyn StorageExternalities<Infallible> + 'static;
+		type Event = ();
+
+		fn execute_block(
+			&self,
+			block: &DummyBlock,
+			state: &mut Self::Externalities,
+		) -> Result<(), CounterError> {
+			CounterExecutor::default().execute_block(block, state)
+		}
+	}
+
+	impl ExtrinsicBuilder for TimestampExecutor {
+		type BuildBlock = DummyBlock;
+		type Inherent = u64;
+		type Extrinsic = u64;
+
+		fn initialize_block(
+			&self,
+			parent_block: &DummyBlock,
+			state: &mut Self::Externalities,
+			timestamp: u64,
+		) -> Result<DummyBlock, CounterError> {
+			state.write_storage(TIMESTAMP_KEY.to_vec(), timestamp.to_le_bytes().to_vec());
+			CounterExecutor::default().initialize_block(parent_block, state, ())
+		}
+
+		fn apply_extrinsic(
+			&self,
+			block: &mut DummyBlock,
+			extrinsic: u64,
+			state: &mut Self::Externalities,
+		) -> Result<Weight, CounterError> {
+			CounterExecutor::default().apply_extrinsic(block, extrinsic, state)
+		}
+
+		fn finalize_block(
+			&self,
+			block: &mut DummyBlock,
+			state: &mut Self::Externalities,
+		) -> Result<(), CounterError> {
+			CounterExecutor::default().finalize_block(block, state)
+		}
+	}
+
+	struct TimestampProvider(u64);
+
+	impl InherentDataProvider<u64> for TimestampProvider {
+		fn provide(&self, timestamp: &mut u64) {
+			*timestamp = self.0;
+		}
+	}
+
+	#[test]
+	fn block_is_produced_on_genesis() {
+		let producer = BlockProducer::new(CounterExecutor::default(), test_backend());
+
+		let (block, _) = producer.produce_one((), Vec::new()).unwrap();
+		assert_eq!(block.parent_id(), Some(producer.backend().genesis()));
+	}
+
+	#[test]
+	fn inherent_comes_from_registered_providers() {
+		let producer = BlockProducer::new(TimestampExecutor, test_backend());
+		let mut providers = InherentDataProviders::new();
+		providers.register(TimestampProvider(1_600_000_000));
+
+		let (block, mut state) = producer.produce_with_providers(&providers, vec![2]).unwrap();
+		assert_eq!(block.parent, Some(0));
+		assert_eq!(counter(&mut state), 2);
+		let state: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+		assert_eq!(state.read_storage(TIMESTAMP_KEY), Ok(Some(1_600_000_000u64.to_le_bytes().to_vec())));
+	}
+
+	#[test]
+	fn block_is_filled_to_weight_limit() {
+		let producer = BlockProducer::new(CounterExecutor { max_weight: 5 }, test_backend());
+
+		let (block, mut state) = producer.produce_one((), vec![2, 3]).unwrap();
+		assert_eq!(block.parent, Some(0));
+		assert_eq!(counter(&mut state), 5);
+
+		match producer.produce_one((), vec![2, 2, 2]) {
+			Err(Error::BlockFull) => (),
+			_ => panic!("Expected block full"),
+		}
+	}
+
+	#[test]
+	fn last_extrinsic_is_rolled_back() {
+		let producer = BlockProducer::new(CounterExecutor { max_weight: 10 }, test_backend());
+		let mut pending = producer.begin_block(()).unwrap();
+
+		pending.apply(1).unwrap();
+		pending.apply(2).unwrap();
+		pending.apply(4).unwrap();
+		assert!(pending.rollback_last());
+		assert!(!pending.rollback_last());
+		assert_eq!(pending.weight(), 3);
+
+		match pending.apply(8) {
+			Err(Error::BlockFull) => (),
+			_ => panic!("Expected block full"),
+		}
+		assert_eq!(pending.weight(), 3);
+
+		let (_, mut state) = pending.finalize().unwrap();
+		assert_eq!(counter(&mut state), 3);
+	}
+
+	#[test]
+	fn block_is_built_on_known_parents_only() {
+		let mut backend = test_backend();
+		import_chain(&mut backend, &[DummyBlock::new(1, 0), DummyBlock::new(2, 1)], true).unwrap();
+		import_chain(&mut backend, &[DummyBlock::new(3, 1)], false).unwrap();
+		let producer = BlockProducer::new(CounterExecutor::default(), backend);
+
+		match producer.begin_block_on(&9, (), true) {
+			Err(Error::UnknownParent) => (),
+			_ => panic!("Expected unknown parent"),
+		}
+		match producer.begin_block_on(&3, (), false) {
+			Err(Error::NonCanonParent) => (),
+			_ => panic!("Expected non-canon parent"),
+		}
+
+		let (block, _) = producer.begin_block_on(&3, (), true).unwrap().finalize().unwrap();
+		assert_eq!(block.parent, Some(3));
+		let (block, _) = producer.begin_block_on(&1, (), false).unwrap().finalize().unwrap();
+		assert_eq!(block.parent, Some(1));
+	}
+}
@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+use crate::Block;
+use crate::backend::TreeRoute;
+use crate::import::{BlockImporter, SharedBlockImporter};
+
+/// An importer that records ids of all successfully imported blocks.
+pub struct RecordingImporter<I: BlockImporter> {
+	importer: I,
+	recorded: Arc<Mutex<Vec<<I::Block as Block>::Identifier>>>,
+}
+
+impl<I: BlockImporter> RecordingImporter<I> {
+	/// Create a new recording importer.
+	pub fn new(importer: I) -> Self {
+		Self {
+			importer,
+			recorded: Arc::new(Mutex::new(Vec::new())),
+		}
+	}
+
+	/// Get ids of all imported blocks, in import order.
+	pub fn recorded(&self) -> Vec<<I::Block as Block>::Identifier> {
+		self.recorded.lock().expect("Lock is poisoned").clone()
+	}
+}
+
+impl<I: BlockImporter + Clone> Clone for RecordingImporter<I> {
+	fn clone(&self) -> Self {
+		Self {
+			importer: self.importer.clone(),
+			recorded: self.recorded.clone(),
+		}
+	}
+}
+
+impl<I: BlockImporter> BlockImporter for RecordingImporter<I> {
+	type Block = I::Block;
+	type Error = I::Error;
+
+	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error> {
+		let id = block.id();
+		self.importer.import_block(block)?;
+		self.recorded.lock().expect("Lock is poisoned").push(id);
+		Ok(())
+	}
+
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		let id = block.id();
+		let route = self.importer.import_block_with_route(block)?;
+		self.recorded.lock().expect("Lock is poisoned").push(id);
+		Ok(route)
+	}
+}
+
+impl<I: SharedBlockImporter> SharedBlockImporter for RecordingImporter<I> {
+	fn import_block(
+		&self,
+		block: <Self as BlockImporter>::Block
+	) -> Result<(), <Self as BlockImporter>::Error> {
+		let id = block.id();
+		SharedBlockImporter::import_block(&self.importer, block)?;
+		self.recorded.lock().expect("Lock is poisoned").push(id);
+		Ok(())
+	}
+
+	fn import_block_with_route(
+		&self,
+		block: <Self as BlockImporter>::Block
+	) -> Result<Option<TreeRoute<<Self as BlockImporter>::Block>>, <Self as BlockImporter>::Error> {
+		let id = block.id();
+		let route = SharedBlockImporter::import_block_with_route(&self.importer, block)?;
+		self.recorded.lock().expect("Lock is poisoned").push(id);
+		Ok(route)
+	}
+}
@@ -1,5 +1,6 @@
 use std::error as stderror;
 use crate::Block;
+use crate::backend::TreeRoute;
 
 /// Trait used for committing blocks, usually built on top of a backend.
 pub trait BlockImporter {
@@ -10,12 +11,34 @@ pub trait BlockImporter {
 
 	/// Commit a block into the backend, and handle consensus and auxiliary.
 	fn import_block(&mut self, block: Self::Block) -> Result<(), Self::Error>;
+
+	/// Commit a block, returning the tree route from the old head to the new
+	/// head, if the head has changed. Importers that cannot track head
+	/// changes return `None`.
+	fn import_block_with_route(
+		&mut self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		self.import_block(block)?;
+		Ok(None)
+	}
 }
 
 /// Shared block importer.
 pub trait SharedBlockImporter: BlockImporter + Clone {
 	/// Commit a block into the backend, and handle consensus and auxiliary.
 	fn import_block(&self, block: Self::Block) -> Result<(), Self::Error>;
+
+	/// Commit a block, returning the tree route from the old head to the new
+	/// head, if the head has changed. Importers that cannot track head
+	/// changes return `None`.
+	fn import_block_with_route(
+		&self,
+		block: Self::Block
+	) -> Result<Option<TreeRoute<Self::Block>>, Self::Error> {
+		SharedBlockImporter::import_block(self, block)?;
+		Ok(None)
+	}
 }
 
 /// Trait used for committing prebuilt blocks, usually built on top of a backend.
@@ -5,3 +5,6 @@
 pub use blockchain_core::*;
 pub mod import;
 pub mod backend;
+
+#[cfg(test)]
+pub(crate) mod test_support;
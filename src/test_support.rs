@@ -0,0 +1,311 @@
+//! Deterministic helpers for tests: a dummy block type, seeded random
+//! chain generation, and importing generated chains into memory backends.
+
+#![allow(dead_code)]
+
+use std::{fmt, error as stderror};
+use core::convert::Infallible;
+use crate::{Block, VersionedBlock, Auxiliary, BlockExecutor, ExtrinsicBuilder, StorageExternalities, AsExternalities, Weight};
+use crate::backend::{
+	KeyValueMemoryState, MemoryBackend, SharedMemoryBackend, Operation, ImportOperation,
+	Committable, SharedCommittable, MemoryError,
+};
+
+/// Block used in tests, identified by a number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DummyBlock {
+	pub id: u64,
+	pub parent: Option<u64>,
+	pub spec_version: u32,
+}
+
+impl DummyBlock {
+	pub fn genesis() -> Self {
+		Self { id: 0, parent: None, spec_version: 0 }
+	}
+
+	pub fn new(id: u64, parent: u64) -> Self {
+		Self { id, parent: Some(parent), spec_version: 0 }
+	}
+
+	pub fn with_spec_version(self, spec_version: u32) -> Self {
+		Self { spec_version, ..self }
+	}
+}
+
+impl Block for DummyBlock {
+	type Identifier = u64;
+
+	fn id(&self) -> u64 { self.id }
+	fn parent_id(&self) -> Option<u64> { self.parent }
+}
+
+impl VersionedBlock for DummyBlock {
+	fn spec_version(&self) -> u32 { self.spec_version }
+}
+
+/// Auxiliary used in tests, storing a value under a small key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+	pub key: u8,
+	pub value: u64,
+}
+
+impl Auxiliary<DummyBlock> for Note {
+	type Key = u8;
+
+	fn key(&self) -> u8 { self.key }
+}
+
+pub type TestBackend = MemoryBackend<DummyBlock, (), KeyValueMemoryState>;
+pub type SharedTestBackend = SharedMemoryBackend<DummyBlock, (), KeyValueMemoryState>;
+
+pub fn test_backend() -> TestBackend {
+	MemoryBackend::new_with_genesis(DummyBlock::genesis(), Default::default())
+}
+
+pub fn shared_test_backend() -> SharedTestBackend {
+	SharedMemoryBackend::new_with_genesis(DummyBlock::genesis(), Default::default())
+}
+
+/// Error of the counter executor.
+#[derive(Debug)]
+pub struct CounterError;
+
+impl fmt::Display for CounterError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self)
+	}
+}
+
+impl stderror::Error for CounterError { }
+
+/// Executor keeping a counter in storage. Executing a block increments
+/// the counter, and each extrinsic adds its value to the counter, with a
+/// weight equal to its value.
+#[derive(Clone)]
+pub struct CounterExecutor {
+	pub max_weight: Weight,
+}
+
+impl Default for CounterExecutor {
+	fn default() -> Self {
+		Self { max_weight: Weight::MAX }
+	}
+}
+
+const COUNTER_KEY: &[u8] = b"counter";
+
+fn read_counter(state: &dyn StorageExternalities<Infallible>) -> u64 {
+	match state.read_storage(COUNTER_KEY) {
+		Ok(Some(value)) => {
+			let mut bytes = [0u8; 8];
+			bytes.copy_from_slice(&value);
+			u64::from_le_bytes(bytes)
+		},
+		Ok(None) => 0,
+		Err(e) => match e { },
+	}
+}
+
+fn write_counter(state: &mut dyn StorageExternalities<Infallible>, value: u64) {
+	state.write_storage(COUNTER_KEY.to_vec(), value.to_le_bytes().to_vec());
+}
+
+/// Read the counter of the counter executor from a state.
+pub fn counter(state: &mut KeyValueMemoryState) -> u64 {
+	let state: &mut dyn StorageExternalities<Infallible> = state.as_externalities();
+	read_counter(state)
+}
+
+impl BlockExecutor for CounterExecutor {
+	type Error = CounterError;
+	type Block = DummyBlock;
+	type Externalities = dyn StorageExternalities<Infallible> + 'static;
+	type Event = ();
+
+	fn execute_block(
+		&self,
+		_block: &DummyBlock,
+		state: &mut Self::Externalities,
+	) -> Result<(), CounterError> {
+		let value = read_counter(state) + 1;
+		write_counter(state, value);
+		Ok(())
+	}
+}
+
+impl ExtrinsicBuilder for CounterExecutor {
+	type BuildBlock = DummyBlock;
+	type Inherent = ();
+	type Extrinsic = u64;
+
+	fn initialize_block(
+		&self,
+		parent_block: &DummyBlock,
+		_state: &mut Self::Externalities,
+		_inherent: (),
+	) -> Result<DummyBlock, CounterError> {
+		Ok(DummyBlock::new(parent_block.id + 1, parent_block.id))
+	}
+
+	fn max_block_weight(&self) -> Weight {
+		self.max_weight
+	}
+
+	fn apply_extrinsic(
+		&self,
+		_block: &mut DummyBlock,
+		extrinsic: u64,
+		state: &mut Self::Externalities,
+	) -> Result<Weight, CounterError> {
+		let value = read_counter(state) + extrinsic;
+		write_counter(state, value);
+		Ok(extrinsic)
+	}
+
+	fn finalize_block(
+		&self,
+		_block: &mut DummyBlock,
+		_state: &mut Self::Externalities,
+	) -> Result<(), CounterError> {
+		Ok(())
+	}
+}
+
+/// Xorshift generator, so that chains are reproducible from a seed
+/// without extra dependencies.
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: u64) -> Self {
+		Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	/// Uniform in `[0, 1)`.
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+
+	/// Uniform in `[0, n)`.
+	pub fn below(&mut self, n: u64) -> u64 {
+		self.next_u64() % n
+	}
+}
+
+/// Generate `n_blocks` blocks on top of `DummyBlock::genesis()`, with ids
+/// `1..=n_blocks`, each referencing an earlier block as parent. With
+/// probability `fork_probability` a block forks off a random earlier
+/// block, otherwise it extends the previous block. Blocks are returned
+/// parent-first.
+pub fn generate_random_chain(seed: u64, n_blocks: u64, fork_probability: f64) -> Vec<DummyBlock> {
+	let mut rng = Rng::new(seed);
+
+	(1..=n_blocks).map(|id| {
+		let parent = if rng.next_f64() < fork_probability {
+			rng.below(id)
+		} else {
+			id - 1
+		};
+		DummyBlock::new(id, parent)
+	}).collect()
+}
+
+/// Operation importing the blocks with default states, without changing
+/// the head.
+pub fn import_operation(
+	blocks: &[DummyBlock],
+) -> Operation<DummyBlock, KeyValueMemoryState, ()> {
+	Operation {
+		import_block: blocks.iter().map(|block| ImportOperation {
+			block: block.clone(),
+			state: Default::default(),
+		}).collect(),
+		..Default::default()
+	}
+}
+
+/// Import the blocks one operation per block through `commit`, setting
+/// each imported block as head if `set_head` is given.
+pub fn import_chain_with<F>(
+	blocks: &[DummyBlock],
+	set_head: bool,
+	mut commit: F,
+) -> Result<(), MemoryError> where
+	F: FnMut(Operation<DummyBlock, KeyValueMemoryState, ()>) -> Result<(), MemoryError>,
+{
+	for block in blocks {
+		let mut operation = import_operation(std::slice::from_ref(block));
+		if set_head {
+			operation.set_head = Some(block.id);
+		}
+		commit(operation)?;
+	}
+
+	Ok(())
+}
+
+/// Import the blocks into a memory backend, as `import_chain_with`.
+pub fn import_chain(
+	backend: &mut TestBackend,
+	blocks: &[DummyBlock],
+	set_head: bool,
+) -> Result<(), MemoryError> {
+	import_chain_with(blocks, set_head, |operation| backend.commit(operation))
+}
+
+/// Import the blocks into a shared memory backend, as `import_chain_with`.
+pub fn import_shared_chain(
+	backend: &SharedTestBackend,
+	blocks: &[DummyBlock],
+	set_head: bool,
+) -> Result<(), MemoryError> {
+	import_chain_with(blocks, set_head, |operation| backend.commit(operation))
+}
+
+mod tests {
+	use super::*;
+	use crate::backend::ChainQuery;
+
+	#[test]
+	fn generated_chains_are_deterministic() {
+		assert_eq!(
+			generate_random_chain(7, 200, 0.3),
+			generate_random_chain(7, 200, 0.3),
+		);
+		assert_ne!(
+			generate_random_chain(7, 200, 0.3),
+			generate_random_chain(8, 200, 0.3),
+		);
+	}
+
+	#[test]
+	fn generated_chains_import() {
+		for seed in 0..20 {
+			let blocks = generate_random_chain(seed, 100, 0.25);
+			for block in &blocks {
+				assert!(block.parent.unwrap() < block.id);
+			}
+
+			let mut backend = test_backend();
+			import_chain(&mut backend, &blocks, false).unwrap();
+			for block in &blocks {
+				assert!(backend.contains(&block.id).unwrap());
+			}
+
+			let mut backend = test_backend();
+			backend.commit(import_operation(&blocks)).unwrap();
+			let parents = blocks.iter()
+				.filter_map(|block| block.parent)
+				.collect::<std::collections::HashSet<_>>();
+			assert_eq!(backend.leaves().unwrap().len(), blocks.len() + 1 - parents.len());
+		}
+	}
+}